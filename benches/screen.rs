@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) 2014-2021 Mathias Hällman
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use std::io;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// rim is a binary crate, so pull the screen module in directly
+#[allow(dead_code)]
+#[path = "../src/screen.rs"]
+mod screen;
+
+use screen::{Cell, Color, Screen, Size};
+
+const SIZE: Size = Size(50, 80);
+
+const CHARS: &[char] = &['a', 'b', 'c', ' ', '{', '}', 'å', 'あ'];
+const COLORS: &[Color] = &[Color::White, Color::Black, Color::Cyan, Color::BrightRed];
+
+// draw varied content, shifted by |frame| so that every cell differs between
+// consecutive frames
+fn draw(screen: &mut Screen, frame: usize) {
+    let Size(rows, cols) = SIZE;
+    for row in 0..rows {
+        for col in 0..cols {
+            let i = row as usize * cols as usize + col as usize + frame;
+            let character = CHARS[i % CHARS.len()];
+            let fg = COLORS[i % COLORS.len()];
+            let bg = COLORS[(i + 1) % COLORS.len()];
+            screen.put(Cell(row, col), character, fg, bg);
+        }
+    }
+}
+
+fn repaint(c: &mut Criterion) {
+    c.bench_function("repaint every cell changed", |b| {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), SIZE);
+        let mut frame = 0;
+        b.iter(|| {
+            frame += 1;
+            draw(&mut screen, frame);
+            screen.flush();
+        })
+    });
+
+    c.bench_function("repaint one cell changed", |b| {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), SIZE);
+        draw(&mut screen, 0);
+        screen.flush();
+        let mut frame = 0;
+        b.iter(|| {
+            frame += 1;
+            draw(&mut screen, 0);
+            screen.put(Cell(0, 0), CHARS[frame % 2], Color::White, Color::Black);
+            screen.flush();
+        })
+    });
+
+    c.bench_function("repaint nothing changed", |b| {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), SIZE);
+        draw(&mut screen, 0);
+        screen.flush();
+        b.iter(|| {
+            draw(&mut screen, 0);
+            screen.flush();
+        })
+    });
+}
+
+// put every cell again as it already is, leaving only comparing cells to what
+// they hold, with none of the wide characters that overlap their neighbors
+fn diff(c: &mut Criterion) {
+    c.bench_function("diff every cell unchanged", |b| {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), SIZE);
+        let Size(rows, cols) = SIZE;
+        let put_all = |screen: &mut Screen| {
+            for row in 0..rows {
+                for col in 0..cols {
+                    let character = CHARS[(row + col) as usize % 6];
+                    screen.put(Cell(row, col), character, Color::White, Color::Black);
+                }
+            }
+        };
+        put_all(&mut screen);
+        screen.flush();
+        b.iter(|| {
+            put_all(&mut screen);
+            screen.flush();
+        })
+    });
+}
+
+criterion_group!(benches, repaint, diff);
+criterion_main!(benches);
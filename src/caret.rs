@@ -8,9 +8,8 @@
 
 use std::cmp;
 
-use unicode_width::UnicodeWidthChar as CharWidth;
-
 use crate::buffer::Buffer;
+use crate::screen;
 
 /*
  * LineUp/Down: move caret a line up or down while trying to preserve the
@@ -201,7 +200,7 @@ pub fn buffer_to_screen_column(line: usize, column: usize, buffer: &Buffer) -> u
         .map(|chars| {
             chars
                 .take(column)
-                .map(|c| CharWidth::width(c).unwrap_or(0))
+                .map(|c| screen::display_width(c).unwrap_or(0))
                 .sum()
         })
         .unwrap_or(0)
@@ -213,7 +212,7 @@ pub fn screen_to_buffer_column(row: usize, screen_column: usize, buffer: &Buffer
         chars
             .filter(|&c| c != '\n')
             .scan(0, |sum, c| {
-                *sum += CharWidth::width(c).unwrap_or(0);
+                *sum += screen::display_width(c).unwrap_or(0);
                 Some(*sum)
             })
             .take_while(|&sum| sum <= screen_column)
@@ -566,12 +566,15 @@ impl Section {
                 screen,
             );
 
-            for screen_cell in
-                screen::CellIterator::new(border_rect(position, split.orientation, split.fst.size))
-            {
-                let border_char = ' ';
-                let border_color = screen::Color::Cyan;
-                screen.put(screen_cell, border_char, border_color, border_color);
+            let screen::Rect(start, screen::Size(rows, cols)) =
+                border_rect(position, split.orientation, split.fst.size);
+            let border_char = ' ';
+            let border_color = screen::Color::Cyan;
+            match split.orientation {
+                Vertical => screen.draw_vline(start, rows, border_char, border_color, border_color),
+                Horizontal => {
+                    screen.draw_hline(start, cols, border_char, border_color, border_color)
+                }
             }
         }
     }
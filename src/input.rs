@@ -6,13 +6,17 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
+use std::cmp;
+use std::io;
 use std::thread;
+use std::time::Duration;
 
 use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::{FutureExt, StreamExt};
 
 use crate::keymap::{Key, KeyMod, KeySym};
+use crate::screen::{self, Cell, Size, FOCUS_GAINED, FOCUS_LOST};
 
 #[cfg(not(test))]
 const STDIN_FILENO: libc::c_int = 0;
@@ -81,6 +85,7 @@ mod libc_poll {
     pub enum PollResult {
         Ready,
         Timeout,
+        Interrupted,
     }
 
     pub fn poll_fd(fd: c_int, timeout_ms: u16) -> PollResult {
@@ -92,6 +97,12 @@ mod libc_poll {
         };
         let num_fds = 1;
         match (unsafe { poll(&mut pollfd, num_fds, timeout_ms as c_int) }).cmp(&0) {
+            // a signal, like for the terminal being resized, cut the wait short
+            std::cmp::Ordering::Less
+                if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted =>
+            {
+                PollResult::Interrupted
+            }
             std::cmp::Ordering::Less => panic!("Unable to poll stdin."),
             std::cmp::Ordering::Greater => PollResult::Ready,
             std::cmp::Ordering::Equal => PollResult::Timeout,
@@ -103,7 +114,7 @@ mod libc_poll {
  * Events to the input loop.
  */
 #[derive(Clone)]
-enum Event {
+enum LoopEvent {
     Continue,
     Break,
 }
@@ -116,8 +127,8 @@ fn input_loop(
 ) {
     let mut tk = termkey::TermKey::new(fd, termkey::c::Flag::CTRLC);
 
-    let inf = futures::stream::repeat::<Event>(Event::Continue);
-    let killer = kill_rx.into_stream().map(|_| Event::Break);
+    let inf = futures::stream::repeat::<LoopEvent>(LoopEvent::Continue);
+    let killer = kill_rx.into_stream().map(|_| LoopEvent::Break);
 
     let mut event_stream = futures::stream::select(inf, killer);
 
@@ -164,8 +175,8 @@ fn input_loop(
         .block_on(async {
             while let Some(event) = event_stream.next().await {
                 match event {
-                    Event::Continue => input_loop(),
-                    Event::Break => break,
+                    LoopEvent::Continue => input_loop(),
+                    LoopEvent::Break => break,
                 }
             }
         });
@@ -274,9 +285,560 @@ fn translate_mods(mods: termkey::c::KeyMod) -> KeyMod {
     ret
 }
 
+/*
+ * Input is what's read from the terminal: either a key, word that the
+ * terminal was resized, word that it gained or lost focus, as reported
+ * when the screen has focus reporting enabled, word that the process was
+ * continued after being stopped, for the screen to be refreshed, or word that
+ * nothing came in time.
+ */
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Input {
+    Key(Key),
+    Resize,
+    FocusGained,
+    FocusLost,
+    Continued,
+    Timeout,
+}
+
+// how long to wait for the rest of a sequence after an escape before taking it
+// to be the escape key on its own, unless told otherwise
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(25);
+
+/*
+ * InputReader reads input from a file descriptor, decoding it as it comes, for
+ * when input is to be read on demand rather than sent over a channel.
+ */
+#[allow(dead_code)] // not used by the editor yet
+pub struct InputReader {
+    fd: libc::c_int,
+    // bytes read but not yet decoded
+    pending: Vec<u8>,
+    escape_timeout: Duration,
+    // tells whether the terminal was resized since last asked
+    resized: Box<dyn FnMut() -> bool>,
+}
+
+#[allow(dead_code)] // not used by the editor yet
+impl InputReader {
+    #[cfg(not(test))]
+    pub fn on_stdin() -> InputReader {
+        InputReader::on_fd(STDIN_FILENO)
+    }
+
+    // the terminal is expected to be in raw mode, as TermInput sets it up
+    pub fn on_fd(fd: libc::c_int) -> InputReader {
+        InputReader {
+            fd,
+            pending: Vec::new(),
+            escape_timeout: ESCAPE_TIMEOUT,
+            resized: Box::new(resize_signal::take_resized),
+        }
+    }
+
+    // have |resized| tell whether the terminal was resized since last asked,
+    // rather than SIGWINCH as noticed once watch_resize is called
+    pub fn set_resize_source<F>(&mut self, resized: F)
+    where
+        F: FnMut() -> bool + 'static,
+    {
+        self.resized = Box::new(resized);
+    }
+
+    // wait for as long as |timeout| for the rest of a sequence after an escape;
+    // too short and sequences arriving slowly, like over ssh, fall apart, too
+    // long and pressing escape on its own lags
+    pub fn set_escape_timeout(&mut self, timeout: Duration) {
+        self.escape_timeout = timeout;
+    }
+
+    // read the next input, waiting for as long as |timeout| for it to come and
+    // giving Input::Timeout if it doesn't; None means input is gone for good,
+    // like when stdin is closed
+    pub fn read_input(&mut self, timeout: Duration) -> Option<Input> {
+        loop {
+            if (self.resized)() {
+                return Some(Input::Resize);
+            }
+            if let Some((input, len)) = parse_focus(&self.pending) {
+                self.pending.drain(..len);
+                return Some(input);
+            }
+            match parse_key(&self.pending) {
+                (Some(key), len) => {
+                    self.pending.drain(..len);
+                    return Some(Input::Key(key));
+                }
+                (None, 0) => (),
+                (None, len) => {
+                    self.pending.drain(..len);
+                    continue;
+                }
+            }
+            // a sequence that's been started is given a short while to complete
+            let timeout = if self.pending.is_empty() {
+                timeout
+            } else {
+                self.escape_timeout
+            };
+            let timeout_ms = cmp::min(timeout.as_millis(), u16::MAX as u128) as u16;
+            match libc_poll::poll_fd(self.fd, timeout_ms) {
+                libc_poll::PollResult::Ready => (),
+                libc_poll::PollResult::Timeout => {
+                    if (self.resized)() {
+                        return Some(Input::Resize);
+                    }
+                    return Some(self.force_key());
+                }
+                // woken by a signal, which for other than a resize or being
+                // continued is waited out
+                libc_poll::PollResult::Interrupted => {
+                    if (self.resized)() {
+                        return Some(Input::Resize);
+                    } else if screen::is_continued() {
+                        return Some(Input::Continued);
+                    }
+                    continue;
+                }
+            }
+            let mut buffer = [0u8; 64];
+            let count = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            match count {
+                0 => return None, // stdin closed
+                count if count > 0 => self.pending.extend_from_slice(&buffer[..count as usize]),
+                // a signal cut the read short, or there was nothing to read
+                // after all, neither of which is the end of input
+                _ => match io::Error::last_os_error().kind() {
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => continue,
+                    _ => panic!("Unable to read stdin."),
+                },
+            }
+        }
+    }
+
+    // wait for as long as |timeout| for the next event, a resize coming before
+    // any input waiting to be read; |resized| is to tell the new size of the
+    // screen if it changed since last asked, e.g. by way of Screen::update_size;
+    // as with read_input, Event::Timeout is given if nothing happens in time
+    // and None once input is gone
+    pub fn poll_event<F>(&mut self, timeout: Duration, mut resized: F) -> Option<Event>
+    where
+        F: FnMut() -> Option<Size>,
+    {
+        loop {
+            if let Some(size) = resized() {
+                return Some(Event::Resize(size));
+            }
+            match self.read_input(timeout)? {
+                Input::Key(key) => return Some(Event::Key(key)),
+                Input::Resize => continue, // for |resized| to tell the new size
+                Input::FocusGained => return Some(Event::FocusGained),
+                Input::FocusLost => return Some(Event::FocusLost),
+                Input::Continued => return Some(Event::Continued),
+                Input::Timeout => return Some(Event::Timeout),
+            }
+        }
+    }
+
+    // decode what's pending without waiting for the rest of it: an escape that
+    // wasn't followed by a complete sequence is the escape key
+    fn force_key(&mut self) -> Input {
+        if self.pending.first() != Some(&0x1B) {
+            // an incomplete character isn't going to be completed
+            self.pending.clear();
+            return Input::Timeout;
+        }
+        self.pending.remove(0);
+        Input::Key(Key::Sym {
+            sym: KeySym::Escape,
+            mods: KeyMod::MOD_NONE,
+        })
+    }
+}
+
+// decode a report of the terminal gaining or losing focus at the start of
+// |bytes|, if that's what's there
+fn parse_focus(bytes: &[u8]) -> Option<(Input, usize)> {
+    if bytes.starts_with(FOCUS_GAINED.as_bytes()) {
+        Some((Input::FocusGained, FOCUS_GAINED.len()))
+    } else if bytes.starts_with(FOCUS_LOST.as_bytes()) {
+        Some((Input::FocusLost, FOCUS_LOST.len()))
+    } else {
+        None
+    }
+}
+
+/*
+ * Helper module for noticing the terminal being resized, by way of SIGWINCH.
+ */
+mod resize_signal {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static RESIZED: AtomicBool = AtomicBool::new(false);
+
+    #[cfg(not(test))]
+    extern "C" fn on_sigwinch(_: libc::c_int) {
+        RESIZED.store(true, Ordering::SeqCst);
+    }
+
+    #[cfg(not(test))]
+    pub fn watch() {
+        let handler = on_sigwinch as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGWINCH, handler as libc::sighandler_t);
+        }
+    }
+
+    // whether the terminal was resized since last asked
+    pub fn take_resized() -> bool {
+        RESIZED.swap(false, Ordering::SeqCst)
+    }
+}
+
+// have input readers tell when the terminal is resized
+#[cfg(not(test))]
+#[allow(dead_code)] // not used by the editor yet
+pub fn watch_resize() {
+    resize_signal::watch();
+}
+
+/*
+ * Event is anything that can happen to the editor from the outside, funnelling
+ * keys, mouse reports, pastes and focus changes decoded from terminal input
+ * along with resizes and being continued after stopped into one type.
+ */
+#[derive(Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Event {
+    Key(Key),
+    Mouse(MouseEvent),
+    Resize(Size),
+    Paste(String),
+    FocusGained,
+    FocusLost,
+    // for Screen::refresh_if_continued to repaint right away
+    Continued,
+    // nothing happened in time
+    Timeout,
+}
+
+/*
+ * A mouse report, in the SGR format terminals use once asked to with mode 1006.
+ * Buttons are numbered from 1 for the left one.
+ */
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct MouseEvent {
+    pub kind: MouseKind,
+    pub cell: Cell,
+    pub mods: KeyMod,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum MouseKind {
+    Press(u8),
+    Release(u8),
+    Drag(u8),
+    ScrollUp,
+    ScrollDown,
+}
+
+const PASTE_START: &[u8] = b"\x1B[200~";
+const PASTE_END: &[u8] = b"\x1B[201~";
+
+/*
+ * EventReader decodes events from bytes of terminal input fed to it, holding on
+ * to whatever isn't complete yet until more is fed. Resize events don't come
+ * from the input, so they're left to whoever notices the terminal resizing.
+ */
+#[derive(Default)]
+#[allow(dead_code)] // not used by the editor yet
+pub struct EventReader {
+    pending: Vec<u8>,
+}
+
+#[allow(dead_code)] // not used by the editor yet
+impl EventReader {
+    pub fn new() -> EventReader {
+        EventReader::default()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    // the next complete event fed, if any
+    pub fn next_event(&mut self) -> Option<Event> {
+        loop {
+            let (event, len) = parse_event(&self.pending);
+            if len == 0 {
+                return None;
+            }
+            self.pending.drain(..len);
+            if event.is_some() {
+                return event;
+            }
+        }
+    }
+}
+
+// decode the event at the start of |bytes| like parse_key does keys
+fn parse_event(bytes: &[u8]) -> (Option<Event>, usize) {
+    if bytes.starts_with(PASTE_START) {
+        let text = &bytes[PASTE_START.len()..];
+        return match text.windows(PASTE_END.len()).position(|w| w == PASTE_END) {
+            Some(end) => {
+                let paste = String::from_utf8_lossy(&text[..end]).into_owned();
+                (
+                    Some(Event::Paste(paste)),
+                    PASTE_START.len() + end + PASTE_END.len(),
+                )
+            }
+            None => (None, 0),
+        };
+    }
+    if bytes.starts_with(FOCUS_GAINED.as_bytes()) {
+        return (Some(Event::FocusGained), FOCUS_GAINED.len());
+    } else if bytes.starts_with(FOCUS_LOST.as_bytes()) {
+        return (Some(Event::FocusLost), FOCUS_LOST.len());
+    }
+    match bytes {
+        [0x1B, b'[', b'<', rest @ ..] => match rest.iter().position(|&b| b == b'M' || b == b'm') {
+            Some(len) => (
+                parse_mouse(&rest[..len], rest[len] == b'M').map(Event::Mouse),
+                len + 4,
+            ),
+            None => (None, 0),
+        },
+        _ => {
+            let (key, len) = parse_key(bytes);
+            (key.map(Event::Key), len)
+        }
+    }
+}
+
+// decode the parameters of an SGR mouse report: the button along with its
+// modifiers, then the column and row counted from 1
+fn parse_mouse(params: &[u8], pressed: bool) -> Option<MouseEvent> {
+    let params: Vec<u16> = std::str::from_utf8(params)
+        .ok()?
+        .split(';')
+        .map(|param| param.parse().ok())
+        .collect::<Option<_>>()?;
+    let (button, col, row) = match params[..] {
+        [button, col, row] if col > 0 && row > 0 => (button, col, row),
+        _ => return None,
+    };
+    let mut mods = KeyMod::MOD_NONE;
+    if button & 4 != 0 {
+        mods.insert(KeyMod::MOD_SHIFT);
+    }
+    if button & 8 != 0 {
+        mods.insert(KeyMod::MOD_ALT);
+    }
+    if button & 16 != 0 {
+        mods.insert(KeyMod::MOD_CTRL);
+    }
+    let number = (button & 3) as u8 + 1;
+    let kind = if button & 64 != 0 {
+        match button & 3 {
+            0 => MouseKind::ScrollUp,
+            1 => MouseKind::ScrollDown,
+            _ => return None, // scrolling sideways
+        }
+    } else if button & 32 != 0 {
+        MouseKind::Drag(number)
+    } else if pressed {
+        MouseKind::Press(number)
+    } else {
+        MouseKind::Release(number)
+    };
+    Some(MouseEvent {
+        kind,
+        cell: Cell(row - 1, col - 1),
+        mods,
+    })
+}
+
+// decode the key at the start of |bytes|, returning it along with the number of
+// bytes it took up; nothing is consumed when |bytes| ends in the middle of a key
+// so the caller can try again with more, while bytes that don't make up a
+// known key are consumed and dropped
+#[allow(dead_code)] // not used by the editor yet
+pub fn parse_key(bytes: &[u8]) -> (Option<Key>, usize) {
+    let sym = |sym| Key::Sym {
+        sym,
+        mods: KeyMod::MOD_NONE,
+    };
+    match bytes {
+        [] | [0x1B] => (None, 0),
+        [0x1B, b'[', rest @ ..] => match parse_csi(rest) {
+            Some((key, len)) => (key, len + 2),
+            None => (None, 0),
+        },
+        [0x1B, b'O'] => (None, 0),
+        [0x1B, b'O', final_byte, ..] => (ss3_key(*final_byte, KeyMod::MOD_NONE), 3),
+        [0x1B, rest @ ..] => match parse_key(rest) {
+            (Some(key), len) => (Some(with_mods(key, KeyMod::MOD_ALT)), len + 1),
+            (None, 0) => (None, 0),
+            (None, len) => (None, len + 1),
+        },
+        [b'\r', ..] => (Some(sym(KeySym::Enter)), 1),
+        [b'\t', ..] => (Some(sym(KeySym::Tab)), 1),
+        [0x08, ..] | [0x7F, ..] => (Some(sym(KeySym::Backspace)), 1),
+        [b' ', ..] => (Some(sym(KeySym::Space)), 1),
+        [0x00, ..] => (
+            Some(Key::Sym {
+                sym: KeySym::Space,
+                mods: KeyMod::MOD_CTRL,
+            }),
+            1,
+        ),
+        [byte @ 0x01..=0x1F, ..] => {
+            // ctrl-a to ctrl-z, then ctrl-\ to ctrl-_
+            let offset = if *byte <= 0x1A { 0x60 } else { 0x40 };
+            let key = Key::Unicode {
+                codepoint: (byte + offset) as char,
+                mods: KeyMod::MOD_CTRL,
+            };
+            (Some(key), 1)
+        }
+        [first, ..] => {
+            let len = match first {
+                0x00..=0x7F => 1,
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => return (None, 1),
+            };
+            if bytes.len() < len {
+                return (None, 0);
+            }
+            match std::str::from_utf8(&bytes[..len]) {
+                Ok(text) => (
+                    text.chars().next().map(|codepoint| Key::Unicode {
+                        codepoint,
+                        mods: KeyMod::MOD_NONE,
+                    }),
+                    len,
+                ),
+                Err(_) => (None, 1),
+            }
+        }
+    }
+}
+
+// decode a control sequence following ESC [, returning the key, if known, and
+// the length of the sequence, or None if it isn't complete
+fn parse_csi(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    let len = bytes.iter().position(|byte| (0x40..=0x7E).contains(byte))?;
+    let params: Vec<u32> = std::str::from_utf8(&bytes[..len])
+        .unwrap_or("")
+        .split(';')
+        // leave out any sub-parameters, like the alternate keys of the kitty
+        // keyboard protocol
+        .map(|param| param.split(':').next().unwrap_or("").parse().unwrap_or(0))
+        .collect();
+    // modifiers are sent as one more than the bits of shift, alt and ctrl
+    let mods = params.get(1).map_or(KeyMod::MOD_NONE, |&param| {
+        KeyMod::from_bits_truncate(param.saturating_sub(1) as u8)
+    });
+    let sym = |sym| Some(Key::Sym { sym, mods });
+    let key = match bytes[len] {
+        b'~' => match params[0] {
+            1 | 7 => sym(KeySym::Home),
+            2 => sym(KeySym::Insert),
+            3 => sym(KeySym::Delete),
+            4 | 8 => sym(KeySym::End),
+            5 => sym(KeySym::Pageup),
+            6 => sym(KeySym::Pagedown),
+            num @ 11..=15 => Some(Key::Fn {
+                num: num as isize - 10,
+                mods,
+            }),
+            num @ 17..=21 => Some(Key::Fn {
+                num: num as isize - 11,
+                mods,
+            }),
+            num @ 23..=26 => Some(Key::Fn {
+                num: num as isize - 12,
+                mods,
+            }),
+            _ => None,
+        },
+        b'Z' => Some(Key::Sym {
+            sym: KeySym::Tab,
+            mods: KeyMod::MOD_SHIFT,
+        }),
+        // keys sent by their codepoint, as with the kitty keyboard protocol,
+        // which tells apart keys like ctrl-i and tab
+        b'u' => match params[0] {
+            9 => sym(KeySym::Tab),
+            13 => sym(KeySym::Enter),
+            27 => sym(KeySym::Escape),
+            32 => sym(KeySym::Space),
+            127 => sym(KeySym::Backspace),
+            codepoint => std::char::from_u32(codepoint)
+                .filter(|codepoint| !codepoint.is_control())
+                .map(|codepoint| Key::Unicode { codepoint, mods }),
+        },
+        final_byte => ss3_key(final_byte, mods),
+    };
+    Some((key, len + 1))
+}
+
+// the key of a sequence ending in |final_byte| that's shared between ESC O
+// sequences and ESC [ ones
+fn ss3_key(final_byte: u8, mods: KeyMod) -> Option<Key> {
+    let sym = match final_byte {
+        b'A' => KeySym::Up,
+        b'B' => KeySym::Down,
+        b'C' => KeySym::Right,
+        b'D' => KeySym::Left,
+        b'E' => KeySym::Begin,
+        b'H' => KeySym::Home,
+        b'F' => KeySym::End,
+        b'P'..=b'S' => {
+            return Some(Key::Fn {
+                num: (final_byte - b'P') as isize + 1,
+                mods,
+            })
+        }
+        _ => return None,
+    };
+    Some(Key::Sym { sym, mods })
+}
+
+fn with_mods(key: Key, more: KeyMod) -> Key {
+    match key {
+        Key::Fn { num, mods } => Key::Fn {
+            num,
+            mods: mods | more,
+        },
+        Key::Sym { sym, mods } => Key::Sym {
+            sym,
+            mods: mods | more,
+        },
+        Key::Unicode { codepoint, mods } => Key::Unicode {
+            codepoint,
+            mods: mods | more,
+        },
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::mem;
+    use std::rc::Rc;
     use std::thread;
     use std::time::Duration;
 
@@ -287,6 +849,311 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_parse_key() {
+        let none = KeyMod::MOD_NONE;
+        let table: Vec<(&[u8], Option<Key>, usize)> = vec![
+            (
+                b"\x1B[A",
+                Some(Key::Sym {
+                    sym: KeySym::Up,
+                    mods: none,
+                }),
+                3,
+            ),
+            (
+                b"\x1B[1;5C",
+                Some(Key::Sym {
+                    sym: KeySym::Right,
+                    mods: KeyMod::MOD_CTRL,
+                }),
+                6,
+            ),
+            (
+                b"\x1B[6~x",
+                Some(Key::Sym {
+                    sym: KeySym::Pagedown,
+                    mods: none,
+                }),
+                4,
+            ),
+            (
+                b"\x1B[105;5u",
+                Some(Key::Unicode {
+                    codepoint: 'i',
+                    mods: KeyMod::MOD_CTRL,
+                }),
+                8,
+            ),
+            (
+                b"\x1B[9;5u",
+                Some(Key::Sym {
+                    sym: KeySym::Tab,
+                    mods: KeyMod::MOD_CTRL,
+                }),
+                6,
+            ),
+            (
+                b"\x1B[97:65;2u",
+                Some(Key::Unicode {
+                    codepoint: 'a',
+                    mods: KeyMod::MOD_SHIFT,
+                }),
+                10,
+            ),
+            (
+                b"\x1B[15;2~",
+                Some(Key::Fn {
+                    num: 5,
+                    mods: KeyMod::MOD_SHIFT,
+                }),
+                7,
+            ),
+            (b"\x1BOS", Some(Key::Fn { num: 4, mods: none }), 3),
+            (
+                b"\x1Ba",
+                Some(Key::Unicode {
+                    codepoint: 'a',
+                    mods: KeyMod::MOD_ALT,
+                }),
+                2,
+            ),
+            (
+                b"\x03",
+                Some(Key::Unicode {
+                    codepoint: 'c',
+                    mods: KeyMod::MOD_CTRL,
+                }),
+                1,
+            ),
+            (
+                b"\xE3\x81\x82",
+                Some(Key::Unicode {
+                    codepoint: 'あ',
+                    mods: none,
+                }),
+                3,
+            ),
+            // unknown sequences are dropped
+            (b"\x1B[99~a", None, 5),
+            // incomplete ones wait for more
+            (b"\x1B[", None, 0),
+            (b"\x1B[1;5", None, 0),
+            (b"\x1B", None, 0),
+            (b"\xE3\x81", None, 0),
+        ];
+        for (bytes, key, len) in table {
+            assert_eq!(parse_key(bytes), (key, len), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_escape_timeout() {
+        let (reader_fd, writer_fd) = unsafe {
+            let mut fds = [0; 2];
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                panic!("Failed to create pipe");
+            }
+            (fds[0], fds[1])
+        };
+        let write = move |bytes: &[u8]| unsafe {
+            libc::write(
+                writer_fd,
+                bytes.as_ptr() as *const libc::c_void,
+                bytes.len(),
+            );
+        };
+        let sym = |sym| {
+            Some(Input::Key(Key::Sym {
+                sym,
+                mods: KeyMod::MOD_NONE,
+            }))
+        };
+        let mut reader = InputReader::on_fd(reader_fd);
+        reader.set_escape_timeout(Duration::from_millis(1));
+        write(b"\x1B");
+        assert_eq!(
+            reader.read_input(Duration::from_millis(100)),
+            sym(KeySym::Escape)
+        );
+
+        // the rest of the sequence arrives in time
+        reader.set_escape_timeout(Duration::from_millis(500));
+        write(b"\x1B");
+        let rest = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            write(b"[A");
+        });
+        assert_eq!(
+            reader.read_input(Duration::from_millis(100)),
+            sym(KeySym::Up)
+        );
+        rest.join().unwrap();
+        unsafe {
+            libc::close(writer_fd);
+            libc::close(reader_fd);
+        }
+    }
+
+    #[test]
+    fn test_poll_event() {
+        let (reader_fd, writer_fd) = unsafe {
+            let mut fds = [0; 2];
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                panic!("Failed to create pipe");
+            }
+            (fds[0], fds[1])
+        };
+        let mut reader = InputReader::on_fd(reader_fd);
+        let timeout = Duration::from_millis(10);
+        unsafe {
+            libc::write(writer_fd, b"a".as_ptr() as *const libc::c_void, 1);
+        }
+        // a resize noticed while input is waiting goes first
+        let mut size = Some(Size(5, 6));
+        assert_eq!(
+            reader.poll_event(timeout, || size.take()),
+            Some(Event::Resize(Size(5, 6)))
+        );
+        assert_eq!(
+            reader.poll_event(timeout, || size.take()),
+            Some(Event::Key(Key::Unicode {
+                codepoint: 'a',
+                mods: KeyMod::MOD_NONE,
+            }))
+        );
+        assert_eq!(
+            reader.poll_event(timeout, || size.take()),
+            Some(Event::Timeout)
+        );
+        unsafe {
+            libc::close(writer_fd);
+            libc::close(reader_fd);
+        }
+    }
+
+    #[test]
+    fn test_event_reader() {
+        let mut reader = EventReader::new();
+        reader.feed(b"a\x1B[I\x1B[<0;3;2M\x1B[<64;1;1M\x1B[200~hi\x1B[A");
+        let mut events = Vec::new();
+        while let Some(event) = reader.next_event() {
+            events.push(event);
+        }
+        // the paste isn't over until its end is seen
+        reader.feed(b"\x1B[201~\x1B[O\x1B[<0;3;2m\x1B[B");
+        while let Some(event) = reader.next_event() {
+            events.push(event);
+        }
+        let mouse = |kind, cell, mods| Event::Mouse(MouseEvent { kind, cell, mods });
+        assert_eq!(
+            events,
+            vec![
+                Event::Key(Key::Unicode {
+                    codepoint: 'a',
+                    mods: KeyMod::MOD_NONE,
+                }),
+                Event::FocusGained,
+                mouse(MouseKind::Press(1), Cell(1, 2), KeyMod::MOD_NONE),
+                mouse(MouseKind::ScrollUp, Cell(0, 0), KeyMod::MOD_NONE),
+                Event::Paste("hi\x1B[A".to_string()),
+                Event::FocusLost,
+                mouse(MouseKind::Release(1), Cell(1, 2), KeyMod::MOD_NONE),
+                Event::Key(Key::Sym {
+                    sym: KeySym::Down,
+                    mods: KeyMod::MOD_NONE,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_input() {
+        let (reader_fd, writer_fd) = unsafe {
+            let mut fds = [0; 2];
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                panic!("Failed to create pipe");
+            }
+            (fds[0], fds[1])
+        };
+        let key = |codepoint| Key::Unicode {
+            codepoint,
+            mods: KeyMod::MOD_NONE,
+        };
+        let sym = |sym| Key::Sym {
+            sym,
+            mods: KeyMod::MOD_NONE,
+        };
+        // pairs of input bytes and the inputs expected to be read from them
+        let table: Vec<(&[u8], Vec<Input>)> = vec![
+            (b"a", vec![Input::Key(key('a'))]),
+            (
+                b"\x1B[A\xE3\x81\x82",
+                vec![Input::Key(sym(KeySym::Up)), Input::Key(key('あ'))],
+            ),
+            (
+                b"\x1Bb\x1B[B",
+                vec![
+                    Input::Key(Key::Unicode {
+                        codepoint: 'b',
+                        mods: KeyMod::MOD_ALT,
+                    }),
+                    Input::Key(sym(KeySym::Down)),
+                ],
+            ),
+            // an escape not followed by anything is the escape key
+            (b"\x1B", vec![Input::Key(sym(KeySym::Escape))]),
+            (b"\x1B[I\x1B[O", vec![Input::FocusGained, Input::FocusLost]),
+            // sequences that merely look a bit like focus reports are keys
+            (
+                b"\x1BOP\x1B[2~\x1B[1;5D",
+                vec![
+                    Input::Key(Key::Fn {
+                        num: 1,
+                        mods: KeyMod::MOD_NONE,
+                    }),
+                    Input::Key(sym(KeySym::Insert)),
+                    Input::Key(Key::Sym {
+                        sym: KeySym::Left,
+                        mods: KeyMod::MOD_CTRL,
+                    }),
+                ],
+            ),
+        ];
+        let resized = Rc::new(std::cell::Cell::new(false));
+        let mut reader = InputReader::on_fd(reader_fd);
+        reader.set_resize_source({
+            let resized = resized.clone();
+            move || resized.replace(false)
+        });
+        for (bytes, inputs) in table {
+            unsafe {
+                libc::write(
+                    writer_fd,
+                    bytes.as_ptr() as *const libc::c_void,
+                    bytes.len(),
+                );
+            }
+            for input in inputs {
+                let timeout = Duration::from_millis(100);
+                assert_eq!(reader.read_input(timeout), Some(input), "{:?}", bytes);
+            }
+        }
+        let timeout = Duration::from_millis(1);
+        assert_eq!(reader.read_input(timeout), Some(Input::Timeout));
+        resized.set(true);
+        assert_eq!(reader.read_input(timeout), Some(Input::Resize));
+        assert_eq!(reader.read_input(timeout), Some(Input::Timeout));
+        // input is only gone once the other end is closed
+        unsafe {
+            libc::close(writer_fd);
+        }
+        assert_eq!(reader.read_input(timeout), None);
+        unsafe {
+            libc::close(reader_fd);
+        }
+    }
+
     // Simulates stdin by writing bytes to a pipe, then listens for the key
     // outputs and matches with expectations.
     #[test]
@@ -704,13 +704,6 @@ Options:
   --version        Show version.
 ";
 
-#[cfg(not(test))]
-#[derive(serde::Deserialize)]
-struct Args {
-    arg_file: Option<String>,
-    flag_version: bool,
-}
-
 /*
  * Events to the main loop.
  */
@@ -723,21 +716,26 @@ enum Event {
 
 #[cfg(not(test))]
 fn main() {
-    let args: Args = docopt::Docopt::new(USAGE)
-        .and_then(|d| d.deserialize())
+    let args = docopt::Docopt::new(USAGE)
+        .and_then(|d| d.parse())
         .unwrap_or_else(|e| e.exit());
-    if args.flag_version {
+    if args.get_bool("--version") {
         println!("Rim - {}", env!("CARGO_PKG_VERSION"));
         return;
     }
-    let mut screen = Screen::setup().unwrap();
+    // the C library's idea of character widths goes by the locale
+    unsafe { libc::setlocale(libc::LC_CTYPE, b"\0".as_ptr() as *const libc::c_char) };
+    let mut screen = Screen::setup(screen::TermProfile::from_terminfo()).unwrap();
 
     let (key_tx, key_rx) = futures::channel::mpsc::unbounded();
     let _term_input = input::start(key_tx);
 
     let (cmd_tx, cmd_rx) = futures::channel::mpsc::unbounded();
     cmd_tx.unbounded_send(Cmd::ResetLayout).unwrap();
-    let filename = args.arg_file.unwrap_or_else(|| "src/main.rs".to_string());
+    let filename = match args.get_str("<file>") {
+        "" => "src/main.rs".to_string(),
+        file => file.to_string(),
+    };
     cmd_tx
         .unbounded_send(Cmd::WinCmd(WinCmd::OpenBuffer(PathBuf::from(&filename))))
         .unwrap();
@@ -769,6 +767,15 @@ fn main() {
             screen.clear();
         }
 
+        // redraw everything if the terminal may have been messed with while
+        // rim was suspended
+        if screen.refresh_if_continued() {
+            rim.frame_needs_redraw = true;
+            for (_, win) in rim.windows.iter_mut() {
+                win.needs_redraw = true;
+            }
+        }
+
         let mut did_draw = rim.frame_needs_redraw;
 
         // draw frame if necessary
@@ -6,30 +6,78 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
+use std::borrow::Cow;
 use std::cmp;
-#[cfg(not(test))]
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
 use std::iter;
-use std::ops::{Add, Sub};
+use std::mem;
+use std::ops::{Add, Range, Sub};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-#[cfg(not(test))]
+use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthChar as CharWidth;
+use unicode_width::UnicodeWidthStr as StrWidth;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Size(pub u16, pub u16);
 
-#[cfg(not(test))]
 impl Size {
-    fn from_cell(Cell(row, col): Cell) -> Size {
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub const fn new(rows: u16, cols: u16) -> Size {
+        Size(rows, cols)
+    }
+
+    const fn from_cell(Cell(row, col): Cell) -> Size {
         Size(row, col)
     }
+
+    // the number of cells, which may well be more than fits in a u16
+    #[must_use]
+    pub const fn area(&self) -> usize {
+        let Size(rows, cols) = *self;
+        rows as usize * cols as usize
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.area() == 0
+    }
+
+    // the cell just past the bottom right corner of a rect of this size at origo
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub const fn to_cell(self) -> Cell {
+        Cell::from_size(self)
+    }
+}
+
+impl From<(u16, u16)> for Size {
+    fn from((rows, cols): (u16, u16)) -> Size {
+        Size(rows, cols)
+    }
+}
+
+impl From<Size> for (u16, u16) {
+    fn from(Size(rows, cols): Size) -> (u16, u16) {
+        (rows, cols)
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Cell(pub u16, pub u16);
 
-#[cfg(not(test))]
 impl Cell {
-    fn within(self, size: Size) -> Option<Cell> {
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub const fn new(row: u16, col: u16) -> Cell {
+        Cell(row, col)
+    }
+
+    const fn within(self, size: Size) -> Option<Cell> {
         let Cell(cell_row, cell_col) = self;
         let Size(end_row, end_col) = size;
         if cell_row < end_row && cell_col < end_col {
@@ -39,11 +87,67 @@ impl Cell {
         }
     }
 
-    fn from_size(Size(row, col): Size) -> Cell {
+    // the closest cell within |size|, or origo if there are no cells at all
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub fn clamp(self, size: Size) -> Cell {
+        if size.is_empty() {
+            return Cell(0, 0);
+        }
+        let (Cell(row, col), Size(rows, cols)) = (self, size);
+        Cell(cmp::min(row, rows - 1), cmp::min(col, cols - 1))
+    }
+
+    // the same as clamp
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub fn clamp_to(self, size: Size) -> Cell {
+        self.clamp(size)
+    }
+
+    // the rows and columns to move from this cell to |other|, negative when
+    // |other| is above or to the left, unlike subtracting cells which stops at
+    // zero
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub fn delta(self, other: Cell) -> (i32, i32) {
+        let (Cell(row, col), Cell(other_row, other_col)) = (self, other);
+        (other_row as i32 - row as i32, other_col as i32 - col as i32)
+    }
+
+    // the number of rows and columns to move between this cell and |other|,
+    // whichever way
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub fn manhattan(self, other: Cell) -> u32 {
+        let (rows, cols) = self.delta(other);
+        rows.unsigned_abs() + cols.unsigned_abs()
+    }
+
+    const fn from_size(Size(row, col): Size) -> Cell {
+        Cell(row, col)
+    }
+
+    // the size of a rect from origo up to, but not including, this cell
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub const fn to_size(self) -> Size {
+        Size::from_cell(self)
+    }
+}
+
+impl From<(u16, u16)> for Cell {
+    fn from((row, col): (u16, u16)) -> Cell {
         Cell(row, col)
     }
 }
 
+impl From<Cell> for (u16, u16) {
+    fn from(Cell(row, col): Cell) -> (u16, u16) {
+        (row, col)
+    }
+}
+
 impl Add for Cell {
     type Output = Cell;
     fn add(self, rhs: Cell) -> Cell {
@@ -65,27 +169,52 @@ impl Sub for Cell {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Rect(pub Cell, pub Size);
 
 impl Rect {
-    pub fn contains(&self, Cell(row, col): Cell) -> bool {
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub const fn new(start: Cell, size: Size) -> Rect {
+        Rect(start, size)
+    }
+
+    #[must_use]
+    pub const fn contains(&self, Cell(row, col): Cell) -> bool {
         let Rect(Cell(start_row, start_col), Size(rows, cols)) = *self;
         row >= start_row && row < start_row + rows && col >= start_col && col < start_col + cols
     }
+
+    // the rect split into one rect per row, top to bottom
+    #[allow(dead_code)] // not used by the editor yet
+    pub fn rows(&self) -> impl Iterator<Item = Rect> {
+        let Rect(Cell(start_row, col), Size(rows, cols)) = *self;
+        (start_row..start_row + rows).map(move |row| Rect(Cell(row, col), Size(1, cols)))
+    }
+
+    // the rect split into the leftmost |cols| columns and the rest
+    #[allow(dead_code)] // not used by the editor yet
+    #[must_use]
+    pub fn split_vertical(&self, cols: u16) -> (Rect, Rect) {
+        let Rect(Cell(row, col), Size(rows, width)) = *self;
+        let cols = cmp::min(cols, width);
+        (
+            Rect(Cell(row, col), Size(rows, cols)),
+            Rect(Cell(row, col + cols), Size(rows, width - cols)),
+        )
+    }
 }
 
 /*
  * Iterates over a region of the screen, defined by a starting cell and a size.
  */
-#[cfg(not(test))]
+#[derive(Clone)]
 pub struct CellIterator {
     next_cell: Option<Cell>,
     size: Size,
     width: u16,
 }
 
-#[cfg(not(test))]
 impl CellIterator {
     pub fn new(Rect(start, size): Rect) -> CellIterator {
         let Size(_, rel_end_col) = size;
@@ -98,7 +227,6 @@ impl CellIterator {
     }
 }
 
-#[cfg(not(test))]
 impl Iterator for CellIterator {
     type Item = Cell;
 
@@ -111,296 +239,5828 @@ impl Iterator for CellIterator {
         });
         ret
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.next_cell.map_or(0, |Cell(row, col)| {
+            let Size(end_row, end_col) = self.size;
+            (end_row - row - 1) as usize * self.width as usize + (end_col - col) as usize
+        });
+        (remaining, Some(remaining))
+    }
 }
 
+impl ExactSizeIterator for CellIterator {}
+
 /*
  * Screen is the output surface. You can put characters within its borders and
  * clear it again. Go nuts!
  */
-#[cfg(not(test))]
 pub struct Screen {
     size: Size,
     terminal: Terminal,
     buffer: ScreenBuffer,
+    // whether to stick to ASCII for boxes, markers and the like
+    ascii_only: bool,
+    // the columns between tab stops when tabs are expanded
+    tab_width: u16,
+    // the character and style clearing fills the screen with, if not the
+    // terminal's default
+    clear_style: Option<(char, Style)>,
+    // the styles pushed for putting characters in, the last pushed on top
+    style_stack: Vec<Style>,
+    theme: Option<Theme>,
+    // the cells presented since the last flush
+    cells_presented: usize,
+    last_frame_stats: RenderStats,
+    // what all the frames since the stats were last reset took
+    stats: RenderStats,
+    // called with the new size whenever the size changes
+    on_resize: Option<Box<dyn FnMut(Size)>>,
+    // the least time between flushes, if the frame rate is capped
+    min_flush_interval: Option<Duration>,
+    last_flush: Option<Instant>,
+    // where the cursor is left at the end of each flush, if anywhere in
+    // particular, like where text is to be inserted
+    cursor: Option<Cell>,
+}
+
+/*
+ * RenderStats tells what it took to get frames onto the terminal: the number
+ * of cells written, the bytes that took, the number of flushes and the time
+ * spent on the last of them.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    pub cells_written: usize,
+    pub bytes_written: usize,
+    pub flushes: usize,
+    pub flush_micros: u64,
 }
 
-#[cfg(not(test))]
 impl Drop for Screen {
     fn drop(&mut self) {
-        self.terminal.clear();
-        self.terminal.show_cursor();
-        self.terminal.disable_altscreen();
+        if self.terminal.is_tty() {
+            // the main and alternate screens keep separate keyboard flag stacks
+            self.terminal.pop_all_keyboard_flags();
+            self.terminal.restore_title();
+            self.terminal.clear();
+            self.terminal.disable_focus_reporting();
+            self.terminal.show_cursor();
+            self.terminal.reset_cursor_style();
+            self.terminal.disable_altscreen();
+            // there being nothing left to hand back if stopped from now on
+            #[cfg(not(test))]
+            job_control::set_suspend_sequence("");
+        }
     }
 }
 
-#[cfg(not(test))]
+#[cfg_attr(test, allow(dead_code))] // tests don't make use of all these
 impl Screen {
-    pub fn setup() -> Result<Screen, String> {
+    #[cfg(not(test))]
+    // set up the screen on stdout, using the sequences in |profile| for setting
+    // up the terminal and restoring it; it's to be set up before input, for the
+    // terminal modes from before input set them up to be restored when stopped
+    pub fn setup(profile: TermProfile) -> Result<Screen, String> {
         Terminal::new().map_or(
-            Err("Failed creating a terminal for stdout.".to_string()),
+            Err("Failed creating a terminal for stdout, is TERM set right?".to_string()),
             |mut terminal| {
-                terminal.enable_altscreen();
-                terminal.hide_cursor();
-                terminal.clear();
-                Ok(Screen {
-                    size: Size(0, 0),
-                    terminal,
-                    buffer: ScreenBuffer::new(),
-                })
+                terminal.profile = profile;
+                if terminal.is_tty() {
+                    job_control::watch();
+                }
+                let size = term_size::size().map(Size::from);
+                Ok(Screen::with_terminal(terminal).sized(size))
             },
         )
     }
 
+    // refresh the screen if the process was continued after having been stopped
+    // since last time, in which case it needs repainting in full
+    #[cfg(not(test))]
+    #[must_use]
+    pub fn refresh_if_continued(&mut self) -> bool {
+        let continued = job_control::take_continued();
+        if continued {
+            self.refresh();
+        }
+        continued
+    }
+
+    // set up a screen writing to |writer| rather than stdout, sized |size|
+    #[allow(dead_code)] // for tests and benchmarks
+    pub fn with_writer(writer: Box<dyn Write>, size: Size) -> Screen {
+        let mut screen = Screen::with_terminal(Terminal::with_writer(writer));
+        screen.resize(size);
+        screen
+    }
+
+    // size the screen to |size| if it's known, so that the first frame isn't
+    // lost, or leave it without any cells until update_size is called
+    fn sized(mut self, size: Option<Size>) -> Screen {
+        if let Some(size) = size {
+            self.resize(size);
+        }
+        self
+    }
+
+    fn with_terminal(mut terminal: Terminal) -> Screen {
+        // keep output that's not going to a terminal free from garbage
+        if terminal.is_tty() {
+            terminal.enable_altscreen();
+            terminal.hide_cursor();
+            terminal.enable_focus_reporting();
+            terminal.clear();
+        }
+        Screen {
+            size: Size(0, 0),
+            terminal,
+            buffer: ScreenBuffer::new(),
+            ascii_only: false,
+            tab_width: 8,
+            clear_style: None,
+            style_stack: Vec::new(),
+            theme: None,
+            cells_presented: 0,
+            last_frame_stats: RenderStats::default(),
+            stats: RenderStats::default(),
+            on_resize: None,
+            min_flush_interval: None,
+            last_flush: None,
+            cursor: None,
+        }
+    }
+
+    // pick up a change in the size of the terminal, returning whether there was
+    // one, in which case whatever is laid out to fit the screen must be laid
+    // out again
+    #[cfg(not(test))]
+    #[must_use]
     pub fn update_size(&mut self) -> bool {
-        term_size::size()
-            .map(|(rows, cols)| Size(rows, cols))
-            .and_then(|new_size| {
-                if new_size == self.size {
-                    None
-                } else {
-                    self.buffer.resize(new_size);
-                    self.size = new_size;
-                    Some(())
-                }
-            })
-            .is_some()
+        matches!(term_size::size(), Some((rows, cols)) if self.change_size(Size(rows, cols)))
+    }
+
+    // resize to |size| unless already that size, returning whether it changed
+    fn change_size(&mut self, size: Size) -> bool {
+        if size == self.size {
+            return false;
+        }
+        self.resize(size);
+        if let Some(on_resize) = self.on_resize.as_mut() {
+            on_resize(size);
+        }
+        true
+    }
+
+    fn resize(&mut self, size: Size) {
+        self.buffer.resize(size);
+        // the terminal may well have moved the cursor when resized
+        self.terminal.forget_cursor();
+        self.size = size;
     }
 
+    #[must_use]
     pub fn size(&self) -> Size {
         self.size
     }
 
+    // the color |role| has in the screen's theme, or in the default theme
+    #[must_use]
+    pub fn color(&self, role: Role) -> Color {
+        self.theme.unwrap_or_default().color(role)
+    }
+
+    // clear the screen to the terminal's default background, or with the
+    // character in the clear style if there is one, written on the next flush
     pub fn clear(&mut self) {
-        self.terminal.clear();
+        let (fill, style) = match self.clear_style {
+            Some(clear_style) => clear_style,
+            None => {
+                self.terminal.clear();
+                self.buffer.clear();
+                return;
+            }
+        };
+        // without erasing the display double sized rows stay that way
+        let rows: Vec<u16> = self.buffer.line_sizes.keys().copied().collect();
+        for row in rows {
+            self.terminal.set_cursor_position(row, 0);
+            self.terminal.set_line_size(LineSize::Normal);
+        }
         self.buffer.clear();
+        // as after erasing, for when something else may have had the terminal
+        self.terminal.reset_attributes();
+        self.terminal.forget_cursor();
+        for cell in CellIterator::new(Rect(Cell(0, 0), self.size)) {
+            self.buffer.update(cell, fill, style);
+        }
+    }
+
+    // set the terminal up again for when something else, like the shell while
+    // suspended, may have messed with it, leaving it cleared for a full repaint
+    pub fn refresh(&mut self) {
+        if self.terminal.is_tty() {
+            self.terminal.reapply();
+        }
+        self.clear();
     }
 
+    // characters put on the screen are written to the terminal on flush
     pub fn put(&mut self, position: Cell, character: char, fg: Color, bg: Color) {
-        if let Some(Cell(row, col)) = position.within(self.size) {
-            if self.buffer.update(position, character, fg, bg) {
-                self.terminal.set_cursor_position(row, col);
-                self.terminal.set_fg(fg);
-                self.terminal.set_bg(bg);
-                self.terminal.put(character);
-            }
+        self.put_styled(position, character, Style::new(fg, bg));
+    }
+
+    pub fn put_styled(&mut self, position: Cell, character: char, style: Style) {
+        if position.within(self.size).is_some() {
+            self.buffer.update(position, character, style);
+        }
+    }
+
+    pub fn draw_hline(&mut self, start: Cell, len: u16, character: char, fg: Color, bg: Color) {
+        for cell in CellIterator::new(Rect(start, Size(1, len))) {
+            self.put(cell, character, fg, bg);
+        }
+    }
+
+    pub fn draw_vline(&mut self, start: Cell, len: u16, character: char, fg: Color, bg: Color) {
+        for cell in CellIterator::new(Rect(start, Size(len, 1))) {
+            self.put(cell, character, fg, bg);
         }
     }
 
+    // have the cursor left at |position| at the end of each flush, rather than
+    // wherever the last character written leaves it
     pub fn set_cursor_position(&mut self, position: Cell) {
-        if let Some(Cell(row, col)) = position.within(self.size) {
+        if position.within(self.size).is_some() {
+            self.cursor = Some(position);
+        }
+    }
+
+    // move the cursor to where it's to be left, if it's been set and is still
+    // on the screen
+    fn place_cursor(&mut self) {
+        if let Some(Cell(row, col)) = self.cursor.and_then(|cursor| cursor.within(self.size)) {
             self.terminal.set_cursor_position(row, col);
         }
     }
 
     pub fn flush(&mut self) {
-        self.terminal.flush();
+        self.flush_at(Instant::now());
     }
-}
-
-/*
- * ScreenBuffer mirrors what's known to be on the screen, allowing us to draw
- * new information only when necessary.
- */
-#[cfg(not(test))]
-struct ScreenBuffer {
-    cells: Vec<Option<(char, Color, Color)>>,
-    width: u16,
-}
 
-#[cfg(not(test))]
-impl ScreenBuffer {
-    fn new() -> ScreenBuffer {
-        ScreenBuffer {
-            cells: Vec::new(),
-            width: 0,
+    // flush unless the last flush was too recent for the frame rate cap, in
+    // which case what's changed is left to go out with the next flush that isn't
+    fn flush_at(&mut self, now: Instant) {
+        let too_soon = matches!(
+            (self.last_flush, self.min_flush_interval),
+            (Some(last), Some(interval)) if now < last + interval
+        );
+        if !too_soon {
+            self.force_flush_at(now);
         }
     }
 
-    fn resize(&mut self, Size(rows, cols): Size) {
-        let current_size = self.cells.len();
-        let new_size = rows as usize * cols as usize;
-        match new_size.cmp(&current_size) {
-            std::cmp::Ordering::Greater => {
-                self.cells.reserve_exact(new_size);
-                self.cells
-                    .extend(iter::repeat(None).take(new_size - current_size))
-            }
-            std::cmp::Ordering::Less => {
-                self.cells.truncate(new_size);
-                self.cells.shrink_to_fit();
-            }
-            std::cmp::Ordering::Equal => (),
+    fn force_flush_at(&mut self, now: Instant) {
+        self.last_flush = Some(now);
+        let start = Instant::now();
+        // a visible cursor is hidden while the frame is written, lest it be
+        // seen darting about the screen, and shown again once it's been put
+        // where it's left
+        let hide = self.terminal.is_tty()
+            && !self.terminal.plain
+            && self.terminal.cursor_visible
+            && self.buffer.has_changes();
+        if hide {
+            self.terminal.hide_cursor();
         }
-        self.width = cols;
+        self.present();
+        self.place_cursor();
+        if hide {
+            self.terminal.show_cursor();
+        }
+        self.flush_terminal();
+        let frame = RenderStats {
+            cells_written: self.cells_presented,
+            bytes_written: self.terminal.take_bytes_written(),
+            flushes: 1,
+            flush_micros: start.elapsed().as_micros() as u64,
+        };
+        self.stats = RenderStats {
+            cells_written: self.stats.cells_written + frame.cells_written,
+            bytes_written: self.stats.bytes_written + frame.bytes_written,
+            flushes: self.stats.flushes + 1,
+            flush_micros: frame.flush_micros,
+        };
+        self.last_frame_stats = frame;
+        self.cells_presented = 0;
     }
 
-    fn clear(&mut self) {
-        for i in 0..self.cells.len() {
-            self.cells[i] = None;
+    // flush what's been written to the terminal, noting what undoes what it's
+    // been set up with by now for if the process is stopped before next time
+    fn flush_terminal(&mut self) {
+        self.terminal.flush();
+        #[cfg(not(test))]
+        if self.terminal.is_tty() {
+            job_control::set_suspend_sequence(&self.terminal.hand_back_sequence());
         }
     }
 
-    // a character taking up multiple screen columns is represented in the buffer
-    // by one Some(character) followed by Nones in the additional cells it covers
-    fn update(&mut self, Cell(row, col): Cell, character: char, fg: Color, bg: Color) -> bool {
-        let cell = Some((character, fg, bg));
-        let idx = (row as usize * self.width as usize) + col as usize;
-        let buffer_size = self.cells.len();
-        let nones = || {
-            (1..CharWidth::width(character).unwrap_or(1))
-                .map(|i| idx + i)
-                .filter(|i| *i < buffer_size)
-        };
-        let update = self.cells[idx] != cell || nones().any(|i| self.cells[i] != None);
-        if update {
-            self.cells[idx] = cell;
-            for i in nones() {
-                self.cells[i] = None;
+    // write the cells changed since last presented to the terminal, row by row
+    fn present(&mut self) {
+        let Size(rows, _) = self.size;
+        for row in 0..rows {
+            let cols = self.buffer.row_width(row);
+            let mut col = 0;
+            while col < cols {
+                let cell = Cell(row, col);
+                if !self.buffer.is_dirty(cell) {
+                    col += 1;
+                    continue;
+                }
+                self.terminal.set_cursor_position(row, col);
+                let contents = self.buffer.get(cell);
+                let span = match contents {
+                    Some((character, style))
+                        if character != ' '
+                            || self.buffer.is_cluster(cell)
+                            || self.buffer.has_link(cell) =>
+                    {
+                        self.terminal.set_style(style);
+                        self.terminal
+                            .set_link(self.buffer.link(self.buffer.index(cell)));
+                        match self.buffer.tail(cell) {
+                            "" => self.terminal.put(character),
+                            tail => self.terminal.put_str(&format!("{}{}", character, tail)),
+                        }
+                        if self.buffer.has_given_width(cell) {
+                            // there's no telling how far the terminal moved
+                            self.terminal.forget_cursor();
+                        }
+                        self.buffer.span(cell)
+                    }
+                    _ => {
+                        // blank out a whole run of blanks in one go, erasing rather
+                        // than writing spaces when the run reaches the edge and
+                        // erasing leaves the right background behind
+                        let run = self.buffer.dirty_run(cell, contents);
+                        self.terminal.set_link(None);
+                        let erases = match contents {
+                            Some((_, style)) => {
+                                self.terminal.set_style(style);
+                                // erasing doesn't underline or reverse anything
+                                self.terminal.bce && style.attrs.is_empty()
+                            }
+                            None => {
+                                self.terminal.set_default_colors();
+                                true
+                            }
+                        };
+                        if col + run == cols && erases {
+                            self.terminal.erase_line();
+                        } else {
+                            for _ in 0..run {
+                                self.terminal.put(' ');
+                            }
+                        }
+                        run
+                    }
+                };
+                self.buffer.clean(cell, span);
+                self.cells_presented += span as usize;
+                col += span;
+                if col >= cols {
+                    // the cursor position is unreliable once it reaches the edge
+                    self.terminal.forget_cursor();
+                }
             }
         }
-        update
+        // lest whatever else is written end up part of the last link
+        self.terminal.set_link(None);
     }
 }
 
-/*
- * Terminal is a simple wrapper that provides some helpful methods for common
- * ouput operations.
- */
-#[cfg(not(test))]
-struct Terminal {
-    terminal: Box<term::StdoutTerminal>,
-}
+#[allow(dead_code)] // the editor doesn't make use of all of these yet
+impl Screen {
+    // clear the screen by blanking out only the cells with something in them,
+    // which for a mostly blank screen is far less to write than erasing all of
+    // it; unlike clear, it leaves the rows at the sizes they're set to
+    pub fn soft_clear(&mut self) {
+        self.clear_rect(Rect(Cell(0, 0), self.size));
+    }
 
-#[cfg(not(test))]
-impl Terminal {
-    pub fn new() -> Option<Terminal> {
-        term::stdout().map(|terminal| Terminal { terminal })
+    // put characters in |style| with put_char until it's popped, e.g. for a
+    // widget to draw in a style of its own and then leave its parent's be
+    pub fn push_style(&mut self, style: Style) {
+        self.style_stack.push(style);
     }
 
-    pub fn set_fg(&mut self, fg: Color) {
-        self.terminal.fg(fg.to_term_color()).unwrap();
+    // go back to the style pushed before the last, if any
+    pub fn pop_style(&mut self) {
+        self.style_stack.pop();
     }
 
-    pub fn set_bg(&mut self, bg: Color) {
-        self.terminal.bg(bg.to_term_color()).unwrap();
+    // the style last pushed, or the theme's foreground on its background if
+    // there's none
+    pub fn current_style(&self) -> Style {
+        self.style_stack.last().copied().unwrap_or_else(|| {
+            Style::new(self.color(Role::Foreground), self.color(Role::Background))
+        })
     }
 
-    pub fn clear(&mut self) {
-        (write!(self.terminal, "\x1B[2J")).unwrap();
+    // put |character| in the current style
+    pub fn put_char(&mut self, position: Cell, character: char) {
+        self.put_styled(position, character, self.current_style());
     }
 
-    pub fn enable_altscreen(&mut self) {
-        (write!(self.terminal, "\x1B7\x1B[?47h")).unwrap();
+    // have clearing fill the screen with |character| colored |fg| on |bg|,
+    // e.g. spaces so that a themed background doesn't flash the terminal's
+    // default one
+    pub fn set_clear_style(&mut self, character: char, fg: Color, bg: Color) {
+        self.clear_style = Some((character, Style::new(fg, bg)));
     }
 
-    pub fn disable_altscreen(&mut self) {
-        (write!(self.terminal, "\x1B[?47l\x1B8")).unwrap();
+    // hand the terminal back the way it was before the screen was set up, e.g.
+    // for running a shell, until resumed
+    pub fn suspend(&mut self) {
+        if self.terminal.is_tty() {
+            self.terminal.hand_back();
+        }
+        self.flush_terminal();
     }
 
-    pub fn hide_cursor(&mut self) {
-        (write!(self.terminal, "\x1B[?25l")).unwrap();
+    pub fn resume(&mut self) {
+        self.refresh();
     }
 
-    pub fn show_cursor(&mut self) {
-        (write!(self.terminal, "\x1B[?25h")).unwrap();
+    // switch to the alternate screen, where the screen is drawn, unless already
+    // there; it's cleared for a full repaint, as whatever was run on the normal
+    // screen in the meantime may have drawn on it too
+    pub fn enter_alt_screen(&mut self) {
+        if self.terminal.is_tty() && !self.terminal.altscreen {
+            self.terminal.enable_altscreen();
+            self.clear();
+        }
     }
 
-    pub fn set_cursor_position(&mut self, row: u16, col: u16) {
-        // add (1, 1) becase terminal row/col is one-indexed
-        (write!(self.terminal, "\x1B[{};{}H", row + 1, col + 1)).unwrap();
+    // switch back to the normal screen, bringing back what was on it and where
+    // its cursor was, unless already there, e.g. to have a command run on it
+    pub fn leave_alt_screen(&mut self) {
+        if self.terminal.is_tty() {
+            self.terminal.disable_altscreen();
+            self.terminal.flush();
+        }
     }
 
-    pub fn put(&mut self, character: char) {
-        (write!(self.terminal, "{}", character)).unwrap();
+    // use |theme| to resolve colors, or the default theme if None
+    pub fn set_theme(&mut self, theme: Option<Theme>) {
+        self.theme = theme;
     }
 
-    pub fn flush(&mut self) {
-        self.terminal.flush().unwrap();
+    // have characters of ambiguous width take up two columns, as on terminals
+    // using a CJK locale, rather than one; the screen is cleared on a change as
+    // what's on it was laid out for the other width
+    pub fn set_ambiguous_wide(&mut self, ambiguous_wide: bool) {
+        if ambiguous_wide != self.buffer.ambiguous_wide {
+            self.buffer.ambiguous_wide = ambiguous_wide;
+            self.terminal.ambiguous_wide = ambiguous_wide;
+            self.clear();
+        }
     }
-}
 
-/*
- * Color values for terminal output.
- */
-#[allow(dead_code)] // colors are not used much yet
-#[cfg(not(test))]
-#[derive(Clone, Copy, PartialEq)]
-pub enum Color {
-    Black,
-    Red,
-    Green,
-    Yellow,
-    Blue,
-    Magenta,
-    Cyan,
-    White,
-    BrightBlack,
-    BrightRed,
-    BrightGreen,
-    BrightYellow,
-    BrightBlue,
-    BrightMagenta,
-    BrightCyan,
-    BrightWhite,
-}
+    // look up the widths of characters as |mode| says, to match how the terminal
+    // lays them out; the screen is cleared on a change as what's on it was laid
+    // out for the other widths
+    pub fn set_width_mode(&mut self, mode: WidthMode) {
+        if mode != self.buffer.width_mode {
+            self.buffer.width_mode = mode;
+            self.terminal.width_mode = mode;
+            self.clear();
+        }
+    }
 
-#[allow(dead_code)] // colors are not used much yet
-#[cfg(not(test))]
-impl Color {
-    pub fn to_term_color(&self) -> term::color::Color {
-        match *self {
-            Color::Black => term::color::BLACK,
-            Color::Red => term::color::RED,
-            Color::Green => term::color::GREEN,
-            Color::Yellow => term::color::YELLOW,
-            Color::Blue => term::color::BLUE,
-            Color::Magenta => term::color::MAGENTA,
-            Color::Cyan => term::color::CYAN,
-            Color::White => term::color::WHITE,
-            Color::BrightBlack => term::color::BRIGHT_BLACK,
-            Color::BrightRed => term::color::BRIGHT_RED,
-            Color::BrightGreen => term::color::BRIGHT_GREEN,
-            Color::BrightYellow => term::color::BRIGHT_YELLOW,
-            Color::BrightBlue => term::color::BRIGHT_BLUE,
-            Color::BrightMagenta => term::color::BRIGHT_MAGENTA,
-            Color::BrightCyan => term::color::BRIGHT_CYAN,
-            Color::BrightWhite => term::color::BRIGHT_WHITE,
+    // put |replacement| in place of characters that can't be shown, like code
+    // points that are yet to be assigned
+    pub fn set_replacement_char(&mut self, replacement: char) {
+        self.buffer.replacement = replacement;
+    }
+
+    // have |character| take up |width| columns wherever it's put, to make up for
+    // a terminal whose font draws it wider or narrower than it's said to be;
+    // the screen is cleared, as what's on it was laid out for the old width
+    pub fn set_width_override(&mut self, character: char, width: u8) -> Result<(), String> {
+        if width != 1 && width != 2 {
+            return Err(format!("A character can't take up {} columns.", width));
+        }
+        let width = width as usize;
+        if self.buffer.width_overrides.insert(character, width) != Some(width) {
+            self.clear();
         }
+        Ok(())
     }
-}
 
-/*
- * Helper module to capture the ugly. Provides a mean to poll the screen size.
- */
-#[cfg(not(test))]
-mod term_size {
-    const STDOUT_FILENO: libc::c_int = 1;
+    // have |f| called with the new size each time the size changes, after the
+    // screen has been resized, so content can be laid out again
+    pub fn on_resize(&mut self, f: impl FnMut(Size) + 'static) {
+        self.on_resize = Some(Box::new(f));
+    }
 
-    #[cfg(target_os = "macos")]
-    const TIOCGWINSZ: libc::c_ulong = 0x40087468;
-    #[cfg(target_os = "linux")]
-    const TIOCGWINSZ: libc::c_ulong = 0x5413;
+    // what it took to present the frame last flushed
+    #[must_use]
+    pub fn last_frame_stats(&self) -> RenderStats {
+        self.last_frame_stats
+    }
 
-    pub fn size() -> Option<(u16, u16)> {
-        #[allow(dead_code)] // not interested in pixel sizes
-        struct WinSize {
-            rows: libc::c_ushort,     // rows, in screen cells
-            cols: libc::c_ushort,     // columns, in screen cells
-            h_pixels: libc::c_ushort, // horizontal size, pixels
-            v_pixels: libc::c_ushort, // vertical size, pixels
+    // what it took to present all the frames flushed since the screen was set
+    // up or reset_stats was last called
+    #[must_use]
+    pub fn stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = RenderStats::default();
+    }
+
+    // flush at most |fps| times a second, holding back flushes coming sooner
+    // than that until the next one that doesn't, or as often as asked if zero
+    pub fn set_max_fps(&mut self, fps: u32) {
+        self.min_flush_interval = Some(fps)
+            .filter(|&fps| fps > 0)
+            .map(|fps| Duration::from_secs(1) / fps);
+    }
+
+    // flush regardless of any cap on the frame rate, e.g. before suspending
+    pub fn force_flush(&mut self) {
+        self.force_flush_at(Instant::now());
+    }
+
+    // flush whenever more than |bytes| of output has piled up, rather than only
+    // when asked to, or never if None
+    pub fn set_flush_threshold(&mut self, bytes: Option<usize>) {
+        self.terminal.out.threshold = bytes;
+    }
+
+    // get the cursor out of the way, in the bottom right corner
+    pub fn park_cursor(&mut self) {
+        if !self.size.is_empty() {
+            let Size(rows, cols) = self.size;
+            self.set_cursor_position(Cell(rows - 1, cols - 1));
         }
+    }
 
-        unsafe {
-            let mut size = WinSize {
-                rows: 0,
-                cols: 0,
-                h_pixels: 0,
-                v_pixels: 0,
-            };
-            match libc::ioctl(STDOUT_FILENO, TIOCGWINSZ, &mut size) {
-                0 => Some((size.rows, size.cols)),
-                _ => None,
+    // have |row| drawn double width, or double height, its columns then being
+    // halved, or normal sized again; which is a line attribute of the terminal
+    // rather than something each character is drawn with
+    pub fn set_line_size(&mut self, row: u16, size: LineSize) {
+        if Cell(row, 0).within(self.size).is_some() && self.buffer.line_size(row) != size {
+            self.terminal.set_cursor_position(row, 0);
+            self.terminal.set_line_size(size);
+            self.buffer.set_line_size(row, size);
+        }
+    }
+
+    // show or hide the cursor, e.g. hiding it while redrawing a lot to keep it
+    // from jumping around
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        if self.terminal.is_tty() && visible != self.terminal.cursor_visible {
+            if visible {
+                self.terminal.show_cursor();
+            } else {
+                self.terminal.hide_cursor();
+            }
+        }
+    }
+
+    // draw the cursor as |shape|
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.terminal.set_cursor_shape(shape);
+    }
+
+    // have the cursor blink, or hold it steady as for screen recordings
+    pub fn set_cursor_blink(&mut self, blink: bool) {
+        self.terminal.set_cursor_blink(blink);
+    }
+
+    // remember where the cursor is, e.g. as put by set_cursor_position, for
+    // restore_cursor to bring it back there after drawing elsewhere
+    pub fn save_cursor(&mut self) {
+        self.present();
+        self.place_cursor();
+        self.terminal.save_cursor();
+    }
+
+    // bring the cursor back to where save_cursor found it, once what's been put
+    // on the screen since has been presented
+    pub fn restore_cursor(&mut self) {
+        self.present();
+        self.terminal.restore_cursor();
+    }
+
+    // whether the terminal is too dumb for colors and cursor movement, leaving
+    // the screen to write only characters and the newlines between rows
+    #[must_use]
+    pub fn is_plain(&self) -> bool {
+        self.terminal.plain
+    }
+
+    // label the terminal window or tab with |title|
+    pub fn set_title(&mut self, title: &str) {
+        if self.terminal.is_tty() {
+            self.terminal.set_title(title);
+        }
+    }
+
+    // put |text| on the system clipboard, false if it had to be cut short
+    pub fn copy_to_clipboard(&mut self, text: &str) -> bool {
+        !self.terminal.is_tty() || self.terminal.set_clipboard(text)
+    }
+
+    // have the terminal report keys as |flags| asks for, until popped again
+    pub fn push_keyboard_flags(&mut self, flags: KeyboardFlags) {
+        self.terminal.push_keyboard_flags(flags);
+    }
+
+    pub fn pop_keyboard_flags(&mut self) {
+        self.terminal.pop_keyboard_flags();
+    }
+
+    // ask the terminal for its keyboard flags, see parse_keyboard_flags
+    pub fn query_keyboard_flags(&mut self) {
+        self.terminal.query_keyboard_flags();
+        self.terminal.flush();
+    }
+
+    // ask the terminal whether it supports synchronized output, see
+    // parse_synchronized_output
+    pub fn query_synchronized_output(&mut self) {
+        self.terminal.query_synchronized_output();
+        self.terminal.flush();
+    }
+
+    // have the terminal present everything written by a flush at once, for
+    // terminals supporting synchronized output, so as to not tear
+    pub fn set_synchronized_output(&mut self, enabled: bool) {
+        self.terminal.out.synchronized = enabled;
+    }
+
+    // forget what's known to be on the screen, for when something else may have
+    // drawn on it; anything not drawn again is blanked out on the next flush
+    pub fn invalidate(&mut self) {
+        self.invalidate_rect(Rect(Cell(0, 0), self.size));
+        self.terminal.forget_colors();
+        self.terminal.forget_cursor();
+    }
+
+    pub fn invalidate_rect(&mut self, rect: Rect) {
+        for cell in CellIterator::new(rect) {
+            if cell.within(self.size).is_some() {
+                self.buffer.invalidate(cell);
+            }
+        }
+    }
+
+    // insert |n| blank rows at |row|, pushing the rows below down and off the
+    // bottom, by having the terminal do it rather than drawing them all again
+    pub fn insert_lines_at(&mut self, row: u16, n: u16) {
+        let Size(rows, _) = self.size;
+        // a terminal without any columns has no lines to speak of
+        if Cell(row, 0).within(self.size).is_some() && n > 0 {
+            let n = cmp::min(n, rows - row);
+            self.terminal.set_cursor_position(row, 0);
+            // the terminal blanks the new lines with the current background
+            self.terminal.set_default_colors();
+            self.terminal.insert_lines(n);
+            self.buffer.insert_rows(row, n);
+        }
+    }
+
+    // delete |n| rows from |row|, pulling the rows below up, by having the
+    // terminal do it rather than drawing them all again
+    pub fn delete_lines_at(&mut self, row: u16, n: u16) {
+        let Size(rows, _) = self.size;
+        if Cell(row, 0).within(self.size).is_some() && n > 0 {
+            let n = cmp::min(n, rows - row);
+            self.terminal.set_cursor_position(row, 0);
+            // the terminal blanks the lines left at the bottom with the current
+            // background
+            self.terminal.set_default_colors();
+            self.terminal.delete_lines(n);
+            self.buffer.delete_rows(row, n);
+        }
+    }
+
+    // insert |n| blank cells at |position|, pushing the rest of the row right and
+    // off the edge, by having the terminal do it rather than drawing them again
+    pub fn insert_chars_at(&mut self, position: Cell, n: u16) {
+        let Size(_, cols) = self.size;
+        if let (Some(Cell(row, col)), true) = (position.within(self.size), n > 0) {
+            let n = cmp::min(n, cols - col);
+            let split = self.buffer.covering(position);
+            self.terminal.set_cursor_position(row, col);
+            self.terminal.set_default_colors();
+            self.terminal.insert_chars(n);
+            self.buffer.insert_cells(position, n);
+            // what's left of wide characters split in two, by the insertion or by
+            // the edge, is blanked out
+            if let Some(start) = split {
+                self.buffer.invalidate(start);
+                if col + n < cols {
+                    self.buffer.invalidate(Cell(row, col + n));
+                }
+            }
+            let last = Cell(row, cols - 1);
+            if self.buffer.get(last).is_some() && self.buffer.span(last) > 1 {
+                self.buffer.invalidate(last);
+            }
+        }
+    }
+
+    // delete |n| cells from |position|, pulling the rest of the row left, by
+    // having the terminal do it rather than drawing them again
+    pub fn delete_chars_at(&mut self, position: Cell, n: u16) {
+        let Size(_, cols) = self.size;
+        if let (Some(Cell(row, col)), true) = (position.within(self.size), n > 0) {
+            let n = cmp::min(n, cols - col);
+            let split_start = self.buffer.covering(position);
+            let split_end = Cell(row, col + n)
+                .within(self.size)
+                .and_then(|end| self.buffer.covering(end));
+            self.terminal.set_cursor_position(row, col);
+            self.terminal.set_default_colors();
+            self.terminal.delete_chars(n);
+            self.buffer.delete_cells(position, n);
+            // what's left of wide characters split in two is blanked out
+            if let Some(start) = split_start {
+                self.buffer.invalidate(start);
+            }
+            if split_end.is_some() {
+                self.buffer.invalidate(position);
+            }
+        }
+    }
+
+    // scroll what's within |rect| up by |lines| rows, or down if negative, the
+    // rows scrolled in being blank; the terminal does the scrolling when |rect|
+    // spans the width of the screen, otherwise the cells are copied over to be
+    // drawn again
+    pub fn scroll(&mut self, rect: Rect, lines: i16) {
+        let Size(rows, cols) = self.size;
+        let Rect(Cell(top, left), Size(height, width)) = rect;
+        let height = cmp::min(height, rows.saturating_sub(top));
+        let width = cmp::min(width, cols.saturating_sub(left));
+        let n = cmp::min(lines.unsigned_abs(), height);
+        if n == 0 || width == 0 {
+            return;
+        }
+        let bottom = top + height;
+        if left == 0 && width == cols {
+            // deleting and inserting lines leaves the rows below |rect| in place
+            if lines > 0 {
+                self.delete_lines_at(top, n);
+                self.insert_lines_at(bottom - n, n);
+            } else {
+                self.delete_lines_at(bottom - n, n);
+                self.insert_lines_at(top, n);
+            }
+            return;
+        }
+        // rows are copied over in the order that leaves those still to be
+        // copied untouched
+        let (moved, blanked): (Vec<u16>, _) = if lines > 0 {
+            (
+                (top..bottom - n).collect(),
+                Rect(Cell(bottom - n, left), Size(n, width)),
+            )
+        } else {
+            (
+                (top + n..bottom).rev().collect(),
+                Rect(Cell(top, left), Size(n, width)),
+            )
+        };
+        for row in moved {
+            let from = if lines > 0 { row + n } else { row - n };
+            self.buffer
+                .copy_cells(Cell(from, left), Cell(row, left), width);
+        }
+        self.clear_rect(blanked);
+        // wide characters sticking into |rect| from the left are split in two
+        for row in (top..bottom).filter(|_| left > 0) {
+            let before = Cell(row, left - 1);
+            if self.buffer.get(before).is_some() && self.buffer.span(before) > 1 {
+                self.buffer.invalidate(before);
+            }
+        }
+    }
+
+    // blank out |rect|, leaving it as a clear would
+    pub fn clear_rect(&mut self, rect: Rect) {
+        for cell in CellIterator::new(rect) {
+            if cell.within(self.size).is_some() {
+                self.buffer.erase(cell);
+            }
+        }
+    }
+
+    // fill |rect| with |character|, wide characters covering several columns each
+    pub fn fill_rect(&mut self, rect: Rect, character: char, fg: Color, bg: Color) {
+        let Rect(Cell(_, left), Size(_, cols)) = rect;
+        let width = self.buffer.char_width(character).unwrap_or(1);
+        let width = cmp::max(width as u16, 1);
+        for cell in CellIterator::new(rect) {
+            let Cell(_, col) = cell;
+            // skip covered columns, and don't let a wide character stick out
+            if (col - left) % width == 0 && col + width <= left + cols {
+                self.put(cell, character, fg, bg);
+            }
+        }
+    }
+
+    // put |text| on a row from |start|, returning the number of columns it takes
+    // up; a wide character that doesn't fit is left out along with the rest
+    pub fn put_str(&mut self, start: Cell, text: &str, style: Style) -> u16 {
+        self.put_clusters(
+            start,
+            text.graphemes(true).map(|cluster| (cluster, style)),
+            None,
+        )
+    }
+
+    // put |text| on a row from |start| as a single run of one style, like a
+    // keyword from a syntax highlighter, returning the number of columns it
+    // takes up; the run goes out with one cursor move and one change of style
+    pub fn put_run(&mut self, start: Cell, text: &str, fg: Color, bg: Color, attrs: Attrs) -> u16 {
+        let style = Style { fg, bg, attrs };
+        self.put_str(start, text, style)
+    }
+
+    // put |text| on a row from |start| as a hyperlink to |url|, which terminals
+    // that support them let be opened, e.g. by clicking it, returning the number
+    // of columns it takes up
+    pub fn put_link(&mut self, start: Cell, text: &str, url: &str, fg: Color, bg: Color) -> u16 {
+        // a control character would end the sequence the URL is sent in early
+        let url: String = url.chars().filter(|c| !c.is_control()).collect();
+        let style = Style::new(fg, bg);
+        let styled = text.graphemes(true).map(|cluster| (cluster, style));
+        self.put_clusters(start, styled, Some(&url))
+    }
+
+    // put |character| taking up |width| columns regardless of what its width is
+    // taken to be otherwise, for glyphs like icons the terminal's font draws
+    // wider than they are said to be
+    pub fn put_width(
+        &mut self,
+        position: Cell,
+        character: char,
+        width: u16,
+        fg: Color,
+        bg: Color,
+    ) -> Result<(), String> {
+        if width != 1 && width != 2 {
+            return Err(format!("A character can't take up {} columns.", width));
+        }
+        if position.within(self.size).is_some() {
+            let style = Style::new(fg, bg);
+            let cluster = character.encode_utf8(&mut [0; 4]).to_string();
+            self.buffer
+                .update_with_width(position, &cluster, Some(width as usize), None, style);
+        }
+        Ok(())
+    }
+
+    // put |text| on a row from |start| in the style of the last of |spans| that
+    // covers each character, or |base| if none does; spans are half-open ranges
+    // of character indices
+    pub fn put_spans(
+        &mut self,
+        start: Cell,
+        text: &str,
+        base: Style,
+        spans: &[(usize, usize, Style)],
+    ) {
+        let style_at = |index: usize| {
+            spans
+                .iter()
+                .rev()
+                .find(|&&(start, end, _)| start <= index && index < end)
+                .map_or(base, |&(_, _, style)| style)
+        };
+        // a cluster takes the style of its first character
+        let mut index = 0;
+        let styled = text.graphemes(true).map(|cluster| {
+            let style = style_at(index);
+            index += cluster.chars().count();
+            (cluster, style)
+        });
+        self.put_clusters(start, styled, None);
+    }
+
+    // put |text| on a row from |start| like put_str, but with tabs expanded to
+    // the next tab stop as an arrow followed by dashes, and with whitespace at
+    // the end of |text| shown as dots; these markers are in |trail_style|
+    pub fn put_listchars(&mut self, start: Cell, text: &str, style: Style, trail_style: Style) {
+        let trail_start = text.trim_end().len();
+        let (arrow, dash, dot) = if self.ascii_only {
+            (">", "-", ".")
+        } else {
+            ("▸", "─", "·")
+        };
+        let tab_width = self.tab_width as usize;
+        let mut width = 0;
+        let mut styled = Vec::new();
+        for (index, cluster) in text.grapheme_indices(true) {
+            if cluster == "\t" {
+                let tab = tab_width - width % tab_width;
+                styled.push((arrow.to_string(), trail_style));
+                styled.extend(iter::repeat((dash.to_string(), trail_style)).take(tab - 1));
+                width += tab;
+            } else if index >= trail_start {
+                styled.push((dot.to_string(), trail_style));
+                width += 1;
+            } else {
+                styled.push((cluster.to_string(), style));
+                width += self.buffer.str_width(cluster);
+            }
+        }
+        let styled = styled
+            .iter()
+            .map(|(cluster, style)| (cluster.as_str(), *style));
+        self.put_clusters(start, styled, None);
+    }
+
+    // lay |text| out in |rect| from its top left, wrapping at its right edge onto
+    // rows starting with |wrap_marker|, returning the number of rows it takes
+    // up; a wide character is wrapped whole, and what doesn't fit is left out
+    pub fn put_wrapped(&mut self, rect: Rect, text: &str, style: Style, wrap_marker: char) -> u16 {
+        let Rect(Cell(top, left), Size(rows, cols)) = rect;
+        if rows == 0 {
+            return 0;
+        }
+        let (mut row, mut col) = (0, 0);
+        for cluster in text.graphemes(true) {
+            let width = self.buffer.str_width(cluster) as u16;
+            if width == 0 {
+                continue;
+            } else if col + width > cols {
+                row += 1;
+                if row == rows {
+                    return rows;
+                }
+                self.put_styled(Cell(top + row, left), wrap_marker, style);
+                col = 1;
+                if col + width > cols {
+                    continue;
+                }
+            }
+            self.put_str(Cell(top + row, left + col), cluster, style);
+            col += width;
+        }
+        row + 1
+    }
+
+    // expand tabs to every |tab_width| columns
+    pub fn set_tab_width(&mut self, tab_width: u16) {
+        self.tab_width = cmp::max(1, tab_width);
+    }
+
+    // put |cells|, each a character and its colors, on a row from |start|, wide
+    // characters taking up all the columns they cover and characters that can't
+    // be shown being replaced as with put; what doesn't fit is left out, and
+    // nothing is written to the terminal until the next flush, where
+    // consecutive cells go out without moving the cursor in between
+    pub fn put_cells(&mut self, start: Cell, cells: &[(char, Color, Color)]) {
+        let Cell(row, mut col) = start;
+        let cols = self.buffer.row_width(row);
+        for &(character, fg, bg) in cells {
+            let (_, _, width) = self.buffer.place(character);
+            let width = width as u16;
+            if width == 0 {
+                continue;
+            } else if col.saturating_add(width) > cols {
+                break;
+            }
+            self.put(Cell(row, col), character, fg, bg);
+            col += width;
+        }
+    }
+
+    fn put_clusters<'t, I>(&mut self, start: Cell, styled: I, link: Option<&str>) -> u16
+    where
+        I: Iterator<Item = (&'t str, Style)>,
+    {
+        let Cell(row, col) = start;
+        let cols = self.buffer.row_width(row);
+        if col >= cols {
+            return 0;
+        }
+        let mut width = 0;
+        for (cluster, style) in styled {
+            let cluster_width = self.buffer.str_width(cluster) as u16;
+            if cluster_width == 0 {
+                continue;
+            } else if (col + width).saturating_add(cluster_width) > cols {
+                break;
+            }
+            if Cell(row, col + width).within(self.size).is_some() {
+                self.buffer
+                    .update_with_width(Cell(row, col + width), cluster, None, link, style);
+            }
+            width += cluster_width;
+        }
+        width
+    }
+
+    // the character at |cell| along with its colors, the columns after the
+    // first of a wide character giving that character too, or None if nothing
+    // is there or |cell| is off the screen
+    #[must_use]
+    pub fn get(&self, cell: Cell) -> Option<(char, Color, Color)> {
+        cell.within(self.size)?;
+        let cell = self.buffer.covering(cell).unwrap_or(cell);
+        self.buffer
+            .get(cell)
+            .map(|(character, style)| (character, style.fg, style.bg))
+    }
+
+    // show a folded region on |row| as |marker| in the first column of |rect|,
+    // followed by |summary| cut short to fit, the rest of the row across |rect|
+    // filled with |style|
+    pub fn put_fold(&mut self, row: u16, rect: Rect, summary: &str, marker: char, style: Style) {
+        let Rect(Cell(_, left), Size(_, cols)) = rect;
+        if !rect.contains(Cell(row, left)) {
+            return;
+        }
+        for col in left..left + cols {
+            self.put_styled(Cell(row, col), ' ', style);
+        }
+        self.put_styled(Cell(row, left), marker, style);
+        let summary = truncate(summary, cols as usize - 1, self.ellipsis());
+        self.put_str(Cell(row, left + 1), &summary, style);
+    }
+
+    // fill |row| with |style|, with |left| flush left, |right| flush right and
+    // |center| centered, cutting them short in that order of priority
+    pub fn status_line(&mut self, row: u16, left: &str, center: &str, right: &str, style: Style) {
+        let Size(_, cols) = self.size;
+        for col in 0..cols {
+            self.put_styled(Cell(row, col), ' ', style);
+        }
+        let cols = cols as usize;
+        let ellipsis = self.ellipsis();
+        let left = truncate(left, cols, ellipsis);
+        let left_end = text_width(&left);
+        let right = truncate(right, cols - left_end, ellipsis);
+        let right_start = cols - text_width(&right);
+        let center = truncate(center, right_start - left_end, ellipsis);
+        let center_width = text_width(&center);
+        let center_start = ((cols - center_width) / 2).clamp(left_end, right_start - center_width);
+        self.put_str(Cell(row, 0), &left, style);
+        self.put_str(Cell(row, center_start as u16), &center, style);
+        self.put_str(Cell(row, right_start as u16), &right, style);
+    }
+
+    // put |text| on |row| aligned with the edges of the screen as |align| says,
+    // cut off at the right edge if it doesn't fit whichever way it's aligned
+    pub fn put_str_aligned(&mut self, row: u16, text: &str, align: Align, fg: Color, bg: Color) {
+        let Size(_, cols) = self.size;
+        let width = text
+            .graphemes(true)
+            .map(|cluster| self.buffer.str_width(cluster))
+            .sum::<usize>();
+        let spare = cols.saturating_sub(cmp::min(width, cols as usize) as u16);
+        let col = match align {
+            Align::Left => 0,
+            Align::Center => spare / 2,
+            Align::Right => spare,
+        };
+        self.put_str(Cell(row, col), text, Style::new(fg, bg));
+    }
+
+    // number the rows of |rect| from |first_line| down, right aligned, with
+    // |current_line| in |current_style|; lines are numbered from one on screen,
+    // and if |relative| by their distance to the current line instead, which
+    // itself keeps its own number
+    pub fn gutter(
+        &mut self,
+        rect: Rect,
+        first_line: usize,
+        current_line: usize,
+        relative: bool,
+        style: Style,
+        current_style: Style,
+    ) {
+        let Rect(_, Size(_, width)) = rect;
+        let ellipsis = self.ellipsis();
+        for (line, row_rect) in (first_line..).zip(rect.rows()) {
+            let Rect(Cell(row, col), _) = row_rect;
+            let (number, style) = if line == current_line {
+                (line + 1, current_style)
+            } else if relative {
+                (
+                    cmp::max(line, current_line) - cmp::min(line, current_line),
+                    style,
+                )
+            } else {
+                (line + 1, style)
+            };
+            let number = number.to_string();
+            let number = truncate(&number, width as usize, ellipsis);
+            for cell in CellIterator::new(row_rect) {
+                self.put_styled(cell, ' ', style);
+            }
+            let start = col + width - text_width(&number) as u16;
+            self.put_str(Cell(row, start), &number, style);
+        }
+    }
+
+    // give every cell on |row| the background |bg|, keeping characters and
+    // foregrounds as they are, blank cells becoming spaces
+    pub fn highlight_row(&mut self, row: u16, bg: Color) {
+        let Size(_, cols) = self.size;
+        self.set_bg_range(Cell(row, 0), cols, bg);
+    }
+
+    // give the |len| cells from |start| on its row the background |bg|, like
+    // for a selection, keeping characters and foregrounds as they are and blank
+    // cells becoming spaces; a wide character partly in the range is all of it
+    pub fn set_bg_range(&mut self, start: Cell, len: u16, bg: Color) {
+        let Cell(row, left) = start;
+        if start.within(self.size).is_none() {
+            return;
+        }
+        let blank = Style::new(self.color(Role::Foreground), bg);
+        let mut col = self.buffer.covering(start).map_or(left, |Cell(_, col)| col);
+        while col < left.saturating_add(len) && Cell(row, col).within(self.size).is_some() {
+            col += self.restyle(Cell(row, col), blank, |style| Style { bg, ..style });
+        }
+    }
+
+    // give column |col| of the screen the background |bg| within |rect|, like
+    // for a ruler at the line length limit, if |rect| spans that column
+    pub fn colorcolumn(&mut self, col: u16, rect: Rect, bg: Color) {
+        let Rect(Cell(top, _), Size(rows, _)) = rect;
+        if rect.contains(Cell(top, col)) {
+            for row in top..top + rows {
+                self.set_bg_range(Cell(row, col), 1, bg);
+            }
+        }
+    }
+
+    // give the cells within |rect| covered by any of |ranges|, each a cell and
+    // the number of cells from it on the same row, the style |style|
+    pub fn highlight_matches(&mut self, rect: Rect, ranges: &[(Cell, u16)], style: Style) {
+        let Rect(Cell(_, left), Size(_, cols)) = rect;
+        let matched = |cell: Cell| {
+            ranges
+                .iter()
+                .any(|&(start, len)| Rect(start, Size(1, len)).contains(cell))
+        };
+        for Rect(Cell(row, _), _) in rect.rows() {
+            let mut col = left;
+            while col < left + cols && Cell(row, col).within(self.size).is_some() {
+                let cell = Cell(row, col);
+                col += if matched(cell) {
+                    self.restyle(cell, style, |_| style)
+                } else {
+                    self.buffer.span(cell)
+                };
+            }
+        }
+    }
+
+    // dim everything on the screen but what's within |keep|, like behind a
+    // dialog, by blending colors |factor| of the way to black, blank cells
+    // becoming spaces; drawing the cells again brings back their colors
+    pub fn dim_except(&mut self, keep: Rect, factor: f32) {
+        let Size(rows, cols) = self.size;
+        let blank = Style::new(
+            self.color(Role::Foreground).dim(factor),
+            self.color(Role::Background).dim(factor),
+        );
+        for row in 0..rows {
+            let mut col = 0;
+            while col < cols {
+                let cell = Cell(row, col);
+                col += if keep.contains(cell) {
+                    self.buffer.span(cell)
+                } else {
+                    self.restyle(cell, blank, |style| Style {
+                        fg: style.fg.dim(factor),
+                        bg: style.bg.dim(factor),
+                        ..style
+                    })
+                };
+            }
+        }
+    }
+
+    // draw a cursor at |position| by reversing the colors of what's there, all of
+    // a wide character being covered whichever of its columns |position| is on,
+    // and return the cells covered
+    pub fn draw_cursor(&mut self, position: Cell) -> Option<Rect> {
+        position.within(self.size)?;
+        let start = self.buffer.covering(position).unwrap_or(position);
+        let mut blank = Style::new(self.color(Role::Foreground), self.color(Role::Background));
+        blank.attrs = Attrs::REVERSE;
+        let span = self.restyle(start, blank, |style| Style {
+            attrs: style.attrs ^ Attrs::REVERSE,
+            ..style
+        });
+        Some(Rect(start, Size(1, span)))
+    }
+
+    // restyle the character at |cell| as |restyle| has it, or put a space styled
+    // |blank| if there's none, returning the number of columns the character
+    // covers
+    fn restyle<F>(&mut self, cell: Cell, blank: Style, restyle: F) -> u16
+    where
+        F: Fn(Style) -> Style,
+    {
+        match self.buffer.get(cell) {
+            Some((_, style)) => self.buffer.set_style(cell, restyle(style)),
+            None => self.put_styled(cell, ' ', blank),
+        }
+        self.buffer.span(cell)
+    }
+
+    // fill the leftmost |fraction| of |rect| with |filled|, the rest with |empty|,
+    // the boundary cell taking a partial block to show eighths of a cell
+    pub fn progress_bar(&mut self, rect: Rect, fraction: f32, filled: Color, empty: Color) {
+        const PARTIAL_BLOCKS: [char; 7] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+        let Rect(Cell(top, left), Size(rows, cols)) = rect;
+        let eighths = (fraction.clamp(0.0, 1.0) * cols as f32 * 8.0).round() as u16;
+        let (full, partial) = if self.ascii_only {
+            ((eighths + 4) / 8, 0)
+        } else {
+            (eighths / 8, eighths % 8)
+        };
+        self.fill_rect(Rect(Cell(top, left), Size(rows, full)), ' ', filled, filled);
+        self.fill_rect(
+            Rect(Cell(top, left + full), Size(rows, cols - full)),
+            ' ',
+            empty,
+            empty,
+        );
+        if partial > 0 {
+            let block = PARTIAL_BLOCKS[partial as usize - 1];
+            self.draw_vline(Cell(top, left + full), rows, block, filled, empty);
+        }
+    }
+
+    // draw boxes, markers and the like using plain ASCII rather than Unicode,
+    // for terminals that can't show more than that
+    pub fn set_ascii_only(&mut self, ascii_only: bool) {
+        self.ascii_only = ascii_only;
+    }
+
+    // what text that's cut short ends in
+    fn ellipsis(&self) -> &'static str {
+        if self.ascii_only {
+            "..."
+        } else {
+            "…"
+        }
+    }
+
+    // outline |rect| with a box, leaving its interior untouched
+    pub fn draw_box(&mut self, rect: Rect, fg: Color, bg: Color) {
+        let Rect(Cell(top, left), Size(rows, cols)) = rect;
+        if rows < 2 || cols < 2 {
+            return; // not even room for the corners
+        }
+        let chars = if self.ascii_only {
+            &ASCII_BOX
+        } else {
+            &UNICODE_BOX
+        };
+        let (bottom, right) = (top + rows - 1, left + cols - 1);
+        self.draw_hline(Cell(top, left + 1), cols - 2, chars.horizontal, fg, bg);
+        self.draw_hline(Cell(bottom, left + 1), cols - 2, chars.horizontal, fg, bg);
+        self.draw_vline(Cell(top + 1, left), rows - 2, chars.vertical, fg, bg);
+        self.draw_vline(Cell(top + 1, right), rows - 2, chars.vertical, fg, bg);
+        self.put(Cell(top, left), chars.top_left, fg, bg);
+        self.put(Cell(top, right), chars.top_right, fg, bg);
+        self.put(Cell(bottom, left), chars.bottom_left, fg, bg);
+        self.put(Cell(bottom, right), chars.bottom_right, fg, bg);
+    }
+
+    // the text on the screen as far as it's known, one line per row
+    #[must_use]
+    pub fn dump(&self) -> String {
+        let Size(rows, cols) = self.size;
+        let mut text = String::new();
+        for row in 0..rows {
+            if row > 0 {
+                text.push('\n');
+            }
+            let mut col = 0;
+            while col < cols {
+                let cell = Cell(row, col);
+                match self.buffer.get(cell) {
+                    Some((character, _)) => {
+                        text.push(character);
+                        text.push_str(self.buffer.tail(cell));
+                    }
+                    None => text.push(' '),
+                }
+                col += self.buffer.span(cell);
+            }
+        }
+        text
+    }
+
+    // split |rect| side by side at |left_fraction| of its width, drawing a divider
+    // column of |divider| in between, and return the rects left and right of it
+    pub fn vsplit(
+        &mut self,
+        rect: Rect,
+        left_fraction: f32,
+        divider: char,
+        style: Style,
+    ) -> (Rect, Rect) {
+        let Rect(_, Size(_, cols)) = rect;
+        let content = cols.saturating_sub(1);
+        let left_cols = if content < 2 {
+            content // not enough room for both sides
+        } else {
+            ((content as f32 * left_fraction).round() as u16).clamp(1, content - 1)
+        };
+        let (left, rest) = rect.split_vertical(left_cols);
+        let (line, right) = rest.split_vertical(1);
+        for cell in CellIterator::new(line) {
+            self.put_styled(cell, divider, style);
+        }
+        (left, right)
+    }
+
+    // what's within |rect|, to be put back with restore_region after drawing
+    // over it, like with a popup
+    #[must_use]
+    pub fn save_rect(&self, rect: Rect) -> SavedRegion {
+        let cells = CellIterator::new(rect)
+            .filter(|cell| cell.within(self.size).is_some())
+            .map(|cell| (cell, self.buffer.contents(cell)))
+            .collect();
+        SavedRegion { cells }
+    }
+
+    // put back what |region| was saved with, only the cells that changed since
+    // being drawn again on flush
+    pub fn restore_region(&mut self, region: &SavedRegion) {
+        for (cell, contents) in &region.cells {
+            if cell.within(self.size).is_none() {
+                continue; // the screen has been made smaller since
+            }
+            match contents {
+                Some((cluster, style, given_width, link)) => {
+                    let link = link.as_deref();
+                    self.buffer
+                        .update_with_width(*cell, cluster, *given_width, link, *style);
+                }
+                None => {
+                    self.buffer.erase(*cell);
+                }
+            }
+        }
+    }
+}
+
+/*
+ * SavedRegion holds what the cells of a rect on the screen held, as saved by
+ * Screen::save_rect.
+ */
+#[allow(dead_code)] // saved regions are not used by the editor yet
+pub struct SavedRegion {
+    cells: Vec<(Cell, Option<CellContents>)>,
+}
+
+// the characters a cell holds, its style, and the width it was given and the
+// URL it links to, if any
+type CellContents = (String, Style, Option<usize>, Option<String>);
+
+/*
+ * The characters making up the lines and corners of a box.
+ */
+#[allow(dead_code)] // boxes are not used by the editor yet
+struct BoxChars {
+    horizontal: char,
+    vertical: char,
+    top_left: char,
+    top_right: char,
+    bottom_left: char,
+    bottom_right: char,
+}
+
+#[allow(dead_code)] // boxes are not used by the editor yet
+const UNICODE_BOX: BoxChars = BoxChars {
+    horizontal: '─',
+    vertical: '│',
+    top_left: '┌',
+    top_right: '┐',
+    bottom_left: '└',
+    bottom_right: '┘',
+};
+
+#[allow(dead_code)] // boxes are not used by the editor yet
+const ASCII_BOX: BoxChars = BoxChars {
+    horizontal: '-',
+    vertical: '|',
+    top_left: '+',
+    top_right: '+',
+    bottom_left: '+',
+    bottom_right: '+',
+};
+
+/*
+ * Viewport is a part of the screen that's drawn to in coordinates relative to
+ * its top left corner, anything outside of it being clipped away.
+ */
+#[allow(dead_code)] // viewports are not used by the editor yet
+pub struct Viewport<'a> {
+    screen: &'a mut Screen,
+    origin: Cell,
+    size: Size,
+}
+
+#[allow(dead_code)] // viewports are not used by the editor yet
+impl<'a> Viewport<'a> {
+    pub fn new(screen: &'a mut Screen, origin: Cell, size: Size) -> Viewport<'a> {
+        Viewport {
+            screen,
+            origin,
+            size,
+        }
+    }
+
+    #[must_use]
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    // wide characters that would stick out of the viewport are left out
+    pub fn put(&mut self, position: Cell, character: char, style: Style) {
+        let Cell(_, col) = position;
+        let Size(_, cols) = self.size;
+        let width = self.screen.buffer.char_width(character).unwrap_or(1);
+        let width = cmp::max(width as u16, 1);
+        if position.within(self.size).is_some() && col + width <= cols {
+            self.screen
+                .put_styled(self.origin + position, character, style);
+        }
+    }
+
+    // put |text| on a row from |start|, returning the number of columns it takes
+    // up within the viewport; combining marks stay with the characters they
+    // combine with, as with Screen::put_str
+    pub fn put_str(&mut self, start: Cell, text: &str, style: Style) -> u16 {
+        let Cell(row, col) = start;
+        let Size(rows, cols) = self.size;
+        if col >= cols {
+            return 0;
+        }
+        let mut room = cols - col;
+        let mut width = 0;
+        let mut clusters = Vec::new();
+        for cluster in text.graphemes(true) {
+            let cluster_width = self.screen.buffer.str_width(cluster) as u16;
+            if cluster_width > room {
+                break;
+            }
+            room -= cluster_width;
+            width += cluster_width;
+            clusters.push((cluster, style));
+        }
+        if row < rows {
+            let Cell(origin_row, origin_col) = self.origin;
+            let start = Cell(
+                origin_row.saturating_add(row),
+                origin_col.saturating_add(col),
+            );
+            self.screen.put_clusters(start, clusters.into_iter(), None);
+        }
+        width
+    }
+
+    // fill the part of |rect| within the viewport with |character|
+    pub fn fill(&mut self, rect: Rect, character: char, style: Style) {
+        let Rect(Cell(row, col), Size(rows, cols)) = rect;
+        let Size(max_rows, max_cols) = self.size;
+        let rows = cmp::min(rows, max_rows.saturating_sub(row));
+        let cols = cmp::min(cols, max_cols.saturating_sub(col));
+        let width = self.screen.buffer.char_width(character).unwrap_or(1);
+        let width = cmp::max(width as u16, 1);
+        for cell in CellIterator::new(Rect(Cell(row, col), Size(rows, cols))) {
+            let Cell(_, cell_col) = cell;
+            if (cell_col - col) % width == 0 && cell_col + width <= col + cols {
+                self.put(cell, character, style);
             }
         }
     }
 }
+
+/*
+ * Layer is a rect of cells to be composited onto the screen, cells left as None
+ * being transparent, letting whatever is below show through.
+ */
+#[allow(dead_code)] // layers are not used by the editor yet
+pub struct Layer {
+    origin: Cell,
+    size: Size,
+    cells: Vec<Option<(char, Style)>>,
+}
+
+#[allow(dead_code)] // layers are not used by the editor yet
+impl Layer {
+    // an entirely transparent layer covering |size| cells from |origin|
+    pub fn new(origin: Cell, size: Size) -> Layer {
+        Layer {
+            origin,
+            size,
+            cells: vec![None; size.area()],
+        }
+    }
+
+    pub fn set_origin(&mut self, origin: Cell) {
+        self.origin = origin;
+    }
+
+    // |position| is relative to the origin of the layer
+    pub fn put(&mut self, position: Cell, character: char, style: Style) {
+        if let Some(index) = self.index(position) {
+            self.cells[index] = Some((character, style));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for cell in self.cells.iter_mut() {
+            *cell = None;
+        }
+    }
+
+    // the contents of the layer at screen position |cell|, if not transparent
+    fn get(&self, cell: Cell) -> Option<(char, Style)> {
+        if !Rect(self.origin, self.size).contains(cell) {
+            return None;
+        }
+        self.index(cell - self.origin)
+            .and_then(|index| self.cells[index])
+    }
+
+    fn index(&self, position: Cell) -> Option<usize> {
+        let Size(_, cols) = self.size;
+        position
+            .within(self.size)
+            .map(|Cell(row, col)| row as usize * cols as usize + col as usize)
+    }
+}
+
+/*
+ * Compositor stacks layers on top of each other, the last one added on top,
+ * and flattens them onto the screen.
+ */
+#[allow(dead_code)] // layers are not used by the editor yet
+#[derive(Default)]
+pub struct Compositor {
+    layers: Vec<Layer>,
+}
+
+#[allow(dead_code)] // layers are not used by the editor yet
+impl Compositor {
+    pub fn new() -> Compositor {
+        Compositor { layers: Vec::new() }
+    }
+
+    // put |layer| on top of the others, returning its index
+    pub fn push(&mut self, layer: Layer) -> usize {
+        self.layers.push(layer);
+        self.layers.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Layer {
+        self.layers.remove(index)
+    }
+
+    pub fn layer_mut(&mut self, index: usize) -> Option<&mut Layer> {
+        self.layers.get_mut(index)
+    }
+
+    // put the topmost non-transparent cell of each screen position on the
+    // screen, leaving positions transparent all the way down blank; only what
+    // changed since last time is written to the terminal on flush
+    pub fn flatten(&self, screen: &mut Screen) {
+        let Size(rows, cols) = screen.size();
+        for row in 0..rows {
+            let mut col = 0;
+            while col < cols {
+                let cell = Cell(row, col);
+                let top = self.layers.iter().rev().find_map(|layer| layer.get(cell));
+                let span = match top {
+                    Some((character, style)) => {
+                        screen.put_styled(cell, character, style);
+                        screen.buffer.span(cell)
+                    }
+                    None => {
+                        screen.buffer.erase(cell);
+                        1
+                    }
+                };
+                // the rest of a wide character covers whatever is below it
+                col += span;
+            }
+        }
+    }
+}
+
+// the number of columns |character| takes up on the screen if it's printable
+#[must_use]
+pub fn display_width(character: char) -> Option<usize> {
+    char_width(character, false, WidthMode::UnicodeWidth)
+}
+
+// printable ASCII always takes up one column, so the width tables are only
+// looked up for other characters, which matters for long runs of plain text
+fn is_printable_ascii(character: char) -> bool {
+    (' '..='~').contains(&character)
+}
+
+#[cfg(test)]
+thread_local! {
+    // the width lookups made by this thread, for telling when they're skipped
+    static WIDTH_LOOKUPS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn count_width_lookup() {
+    #[cfg(test)]
+    WIDTH_LOOKUPS.with(|lookups| lookups.set(lookups.get() + 1));
+}
+
+// the number of columns |character| takes up if it's printable, characters of
+// ambiguous width taking up two if |ambiguous_wide| as on CJK terminals; the
+// C library knows nothing of |ambiguous_wide|, going by the locale instead
+fn char_width(character: char, ambiguous_wide: bool, mode: WidthMode) -> Option<usize> {
+    if is_printable_ascii(character) {
+        return Some(1);
+    }
+    count_width_lookup();
+    if mode == WidthMode::Wcwidth {
+        wc_char_width(character)
+    } else if ambiguous_wide {
+        CharWidth::width_cjk(character)
+    } else {
+        CharWidth::width(character)
+    }
+}
+
+fn str_width(text: &str, ambiguous_wide: bool, mode: WidthMode) -> usize {
+    if text.chars().all(is_printable_ascii) {
+        return text.len();
+    }
+    count_width_lookup();
+    if mode == WidthMode::Wcwidth {
+        text.chars().map(|c| wc_char_width(c).unwrap_or(0)).sum()
+    } else if ambiguous_wide {
+        StrWidth::width_cjk(text)
+    } else {
+        StrWidth::width(text)
+    }
+}
+
+extern "C" {
+    fn wcwidth(c: libc::wchar_t) -> libc::c_int;
+}
+
+// the number of columns the C library says |character| takes up in the current
+// locale, if it's printable
+fn wc_char_width(character: char) -> Option<usize> {
+    match unsafe { wcwidth(character as libc::wchar_t) } {
+        width if width < 0 => None,
+        width => Some(width as usize),
+    }
+}
+
+// the number of columns |text| takes up on the screen
+fn text_width(text: &str) -> usize {
+    text.chars().map(|c| display_width(c).unwrap_or(0)).sum()
+}
+
+// |text| cut down to at most |max| columns, ending in an ellipsis if cut; wide
+// characters are left out entirely rather than split at the cut
+#[allow(dead_code)] // not used by the editor yet
+#[must_use]
+pub fn truncate_to_width(text: &str, max: u16) -> Cow<'_, str> {
+    truncate(text, max as usize, "…")
+}
+
+// |text| broken into lines of at most |width| columns, at whitespace where
+// possible and within words too long for a line of their own; line breaks in
+// |text| are kept, while runs of whitespace otherwise come out as one space,
+// and a wide character too wide for a line at all is left out
+#[allow(dead_code)] // not used by the editor yet
+#[must_use]
+pub fn wrap_to_width(text: &str, width: u16) -> Vec<String> {
+    let width = width as usize;
+    let mut lines = Vec::new();
+    if width == 0 {
+        return lines;
+    }
+    for paragraph in text.lines() {
+        let (mut line, mut line_width) = (String::new(), 0);
+        for word in paragraph.split_whitespace() {
+            let word_width = str_width(word, false, WidthMode::UnicodeWidth);
+            if !line.is_empty() && line_width + 1 + word_width <= width {
+                line.push(' ');
+                line.push_str(word);
+                line_width += 1 + word_width;
+                continue;
+            } else if !line.is_empty() {
+                lines.push(mem::take(&mut line));
+                line_width = 0;
+            }
+            for cluster in word.graphemes(true) {
+                let cluster_width = str_width(cluster, false, WidthMode::UnicodeWidth);
+                if cluster_width > width {
+                    continue;
+                } else if line_width + cluster_width > width {
+                    lines.push(mem::take(&mut line));
+                    line_width = 0;
+                }
+                line.push_str(cluster);
+                line_width += cluster_width;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// |text| cut down to at most |width| columns, ending in |ellipsis| if cut, or
+// as much of it as fits
+fn truncate<'a>(text: &'a str, width: usize, ellipsis: &str) -> Cow<'a, str> {
+    if text_width(text) <= width {
+        return Cow::Borrowed(text);
+    }
+    let ellipsis = truncate_chars(ellipsis, width);
+    let width = width - text_width(&ellipsis);
+    let mut truncated = truncate_chars(text, width);
+    truncated.push_str(&ellipsis);
+    Cow::Owned(truncated)
+}
+
+// the characters of |text| up to where they'd take up more than |width| columns
+fn truncate_chars(text: &str, width: usize) -> String {
+    let mut truncated = String::new();
+    let mut used = 0;
+    for character in text.chars() {
+        let char_width = display_width(character).unwrap_or(0);
+        if used + char_width > width {
+            break;
+        }
+        truncated.push(character);
+        used += char_width;
+    }
+    truncated
+}
+
+/*
+ * Cells holds what's in each cell of the screen buffer, the characters and
+ * styles kept apart along with a bitset of the cells that hold anything at all,
+ * which takes less space than a vector of optional pairs and keeps the parts
+ * compared together close by.
+ */
+struct Cells {
+    chars: Vec<char>,
+    styles: Vec<Style>,
+    occupied: Vec<u64>,
+}
+
+impl Cells {
+    fn new() -> Cells {
+        Cells {
+            chars: Vec::new(),
+            styles: Vec::new(),
+            occupied: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    fn is_occupied(&self, idx: usize) -> bool {
+        self.occupied[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn get(&self, idx: usize) -> Option<(char, Style)> {
+        if self.is_occupied(idx) {
+            Some((self.chars[idx], self.styles[idx]))
+        } else {
+            None
+        }
+    }
+
+    // what an unoccupied cell is left holding is never looked at
+    fn set(&mut self, idx: usize, contents: Option<(char, Style)>) {
+        let bit = 1 << (idx % 64);
+        match contents {
+            Some((character, style)) => {
+                self.chars[idx] = character;
+                self.styles[idx] = style;
+                self.occupied[idx / 64] |= bit;
+            }
+            None => self.occupied[idx / 64] &= !bit,
+        }
+    }
+
+    // have |len| cells, the ones added being empty; the vectors are never shrunk,
+    // so a terminal resized back and forth doesn't keep reallocating them
+    fn resize(&mut self, len: usize) {
+        self.chars.resize(len, ' ');
+        self.styles
+            .resize(len, Style::new(Color::White, Color::Black));
+        self.occupied.resize(len.div_ceil(64), 0);
+        // keep the bits past the end clear for when the cells grow again
+        for idx in len..self.occupied.len() * 64 {
+            self.occupied[idx / 64] &= !(1 << (idx % 64));
+        }
+    }
+
+    // empty all the cells
+    fn clear(&mut self) {
+        for bits in self.occupied.iter_mut() {
+            *bits = 0;
+        }
+    }
+
+    // move the cells in |range| left by |n|, those pushed off the start coming
+    // back in at the end
+    fn rotate_left(&mut self, range: Range<usize>, n: usize) {
+        let mut occupied = self.occupancy(range.clone());
+        occupied.rotate_left(n);
+        self.chars[range.clone()].rotate_left(n);
+        self.styles[range.clone()].rotate_left(n);
+        self.set_occupancy(range, occupied);
+    }
+
+    // move the cells in |range| right by |n|, those pushed off the end coming
+    // back in at the start
+    fn rotate_right(&mut self, range: Range<usize>, n: usize) {
+        let mut occupied = self.occupancy(range.clone());
+        occupied.rotate_right(n);
+        self.chars[range.clone()].rotate_right(n);
+        self.styles[range.clone()].rotate_right(n);
+        self.set_occupancy(range, occupied);
+    }
+
+    fn occupancy(&self, range: Range<usize>) -> Vec<bool> {
+        range.map(|idx| self.is_occupied(idx)).collect()
+    }
+
+    fn set_occupancy(&mut self, range: Range<usize>, occupied: Vec<bool>) {
+        for (idx, occupied) in range.zip(occupied) {
+            let bit = 1 << (idx % 64);
+            if occupied {
+                self.occupied[idx / 64] |= bit;
+            } else {
+                self.occupied[idx / 64] &= !bit;
+            }
+        }
+    }
+}
+
+/*
+ * ScreenBuffer mirrors what's known to be on the screen, allowing us to draw
+ * new information only when necessary.
+ */
+struct ScreenBuffer {
+    cells: Cells,
+    // the characters following the first of cells holding a grapheme cluster of
+    // several, by index
+    tails: HashMap<usize, String>,
+    // the widths of cells given explicitly rather than taken from what they hold,
+    // by index
+    widths: HashMap<usize, usize>,
+    // the URLs of cells that are part of hyperlinks, by index
+    links: HashMap<usize, String>,
+    dirty: Vec<bool>,
+    width: u16,
+    // whether characters of ambiguous width take up two columns
+    ambiguous_wide: bool,
+    // where the widths of characters are looked up
+    width_mode: WidthMode,
+    // the widths characters take up regardless of what they're said to be, by
+    // character
+    width_overrides: HashMap<char, usize>,
+    // what's put in place of characters that can't be shown
+    replacement: char,
+    // the sizes of the rows that aren't of the normal size, by row
+    line_sizes: HashMap<u16, LineSize>,
+}
+
+#[cfg_attr(test, allow(dead_code))] // tests don't make use of all these
+impl ScreenBuffer {
+    fn new() -> ScreenBuffer {
+        ScreenBuffer {
+            cells: Cells::new(),
+            tails: HashMap::new(),
+            widths: HashMap::new(),
+            links: HashMap::new(),
+            dirty: Vec::new(),
+            width: 0,
+            ambiguous_wide: false,
+            width_mode: WidthMode::UnicodeWidth,
+            width_overrides: HashMap::new(),
+            replacement: '\u{FFFD}',
+            line_sizes: HashMap::new(),
+        }
+    }
+
+    // the terminal reflows or clears its contents when its width changes, so
+    // nothing is assumed to be left then; otherwise rows are kept in place
+    fn resize(&mut self, size: Size) {
+        let Size(_, cols) = size;
+        if cols != self.width {
+            self.cells.clear();
+            self.tails.clear();
+            self.widths.clear();
+            self.links.clear();
+            self.dirty.clear();
+            self.line_sizes.clear();
+        }
+        let Size(rows, _) = size;
+        self.tails.retain(|&idx, _| idx < size.area());
+        self.widths.retain(|&idx, _| idx < size.area());
+        self.links.retain(|&idx, _| idx < size.area());
+        self.line_sizes.retain(|&row, _| row < rows);
+        self.cells.resize(size.area());
+        self.dirty.resize(size.area(), false);
+        self.width = cols;
+    }
+
+    fn clear(&mut self) {
+        self.cells.clear();
+        for dirty in self.dirty.iter_mut() {
+            *dirty = false;
+        }
+        self.tails.clear();
+        self.widths.clear();
+        self.links.clear();
+        // erasing the whole display makes every line normal sized again
+        self.line_sizes.clear();
+    }
+
+    fn line_size(&self, row: u16) -> LineSize {
+        self.line_sizes
+            .get(&row)
+            .copied()
+            .unwrap_or(LineSize::Normal)
+    }
+
+    // the number of columns on |row|, halved when it's drawn double width
+    fn row_width(&self, row: u16) -> u16 {
+        match self.line_size(row) {
+            LineSize::Normal => self.width,
+            _ => self.width / 2,
+        }
+    }
+
+    // the terminal drops what's beyond the half of a row made double width,
+    // which is left blank to be drawn again if the row is made normal again
+    fn set_line_size(&mut self, row: u16, size: LineSize) {
+        if size == LineSize::Normal {
+            self.line_sizes.remove(&row);
+            return;
+        }
+        self.line_sizes.insert(row, size);
+        for col in self.row_width(row)..self.width {
+            self.invalidate(Cell(row, col));
+        }
+    }
+
+    fn index(&self, Cell(row, col): Cell) -> usize {
+        (row as usize * self.width as usize) + col as usize
+    }
+
+    // the first character of what the cell holds and its style
+    fn get(&self, cell: Cell) -> Option<(char, Style)> {
+        self.cells.get(self.index(cell))
+    }
+
+    // the characters following the first in the cell, if it holds a cluster
+    fn tail(&self, cell: Cell) -> &str {
+        self.tails
+            .get(&self.index(cell))
+            .map_or("", |tail| tail.as_str())
+    }
+
+    // the width given for the cell at |idx|, if any; widths are seldom given,
+    // and not hashing the index when there are none saves much of the time it
+    // takes to put a character
+    fn given_width(&self, idx: usize) -> Option<usize> {
+        if self.widths.is_empty() {
+            return None;
+        }
+        self.widths.get(&idx).copied()
+    }
+
+    // the URL of the hyperlink the cell at |idx| is part of, if any, which is
+    // looked up only if there are any links like with given widths
+    fn link(&self, idx: usize) -> Option<&str> {
+        if self.links.is_empty() {
+            return None;
+        }
+        self.links.get(&idx).map(|url| url.as_str())
+    }
+
+    fn has_link(&self, cell: Cell) -> bool {
+        self.link(self.index(cell)).is_some()
+    }
+
+    fn is_cluster(&self, cell: Cell) -> bool {
+        self.tails.contains_key(&self.index(cell))
+    }
+
+    fn has_given_width(&self, cell: Cell) -> bool {
+        self.widths.contains_key(&self.index(cell))
+    }
+
+    // the number of columns |character| takes up if it's printable, unless its
+    // width is overridden, or those of the replacement if it can't be shown
+    fn char_width(&self, character: char) -> Option<usize> {
+        match self.width_overrides.get(&character) {
+            Some(&width) => Some(width),
+            None => match char_width(character, self.ambiguous_wide, self.width_mode) {
+                None if !character.is_control() => Some(self.replacement_width()),
+                width => width,
+            },
+        }
+    }
+
+    // the number of columns |cluster| takes up, the width of a single character
+    // being taken as for char_width
+    fn str_width(&self, cluster: &str) -> usize {
+        let mut characters = cluster.chars();
+        match (characters.next(), characters.next()) {
+            (Some(character), None) => self.char_width(character).unwrap_or(0),
+            _ => str_width(cluster, self.ambiguous_wide, self.width_mode),
+        }
+    }
+
+    // what's put in a cell for |character|, the width given for it if any and
+    // the number of columns it takes up; an overridden width is given like any
+    // other, as the terminal's idea of it is no more to be trusted, and a
+    // character that can't be shown, like a code point that's yet to be
+    // assigned, is replaced to keep the layout predictable, the width of the
+    // replacement being given too if it can't be shown itself
+    fn place(&self, character: char) -> (char, Option<usize>, usize) {
+        if let Some(&width) = self.width_overrides.get(&character) {
+            return (character, Some(width), width);
+        }
+        match char_width(character, self.ambiguous_wide, self.width_mode) {
+            Some(width) => (character, None, width),
+            None if character.is_control() => (character, None, 1),
+            None => match char_width(self.replacement, self.ambiguous_wide, self.width_mode) {
+                Some(width) => (self.replacement, None, width),
+                None => (self.replacement, Some(1), 1),
+            },
+        }
+    }
+
+    // the number of columns taken up by what replaces characters that can't be
+    // shown
+    fn replacement_width(&self) -> usize {
+        char_width(self.replacement, self.ambiguous_wide, self.width_mode).unwrap_or(1)
+    }
+
+    // the number of columns taken up by what the cell holds, at least one
+    fn span(&self, cell: Cell) -> u16 {
+        let width = match self.get(cell) {
+            Some(_) if self.has_given_width(cell) => self.widths[&self.index(cell)],
+            Some((character, _)) if self.is_cluster(cell) => {
+                let cluster = format!("{}{}", character, self.tail(cell));
+                self.str_width(&cluster)
+            }
+            Some((character, _)) => self.char_width(character).unwrap_or(1),
+            None => 1,
+        };
+        cmp::max(width as u16, 1)
+    }
+
+    // whether the cell has changed since it was last presented
+    fn is_dirty(&self, cell: Cell) -> bool {
+        self.dirty[self.index(cell)]
+    }
+
+    // whether any cell has changed since last presented
+    fn has_changes(&self) -> bool {
+        self.dirty.contains(&true)
+    }
+
+    fn clean(&mut self, cell: Cell, len: u16) {
+        let idx = self.index(cell);
+        let end = cmp::min(idx + len as usize, self.dirty.len());
+        for i in idx..end {
+            self.dirty[i] = false;
+        }
+    }
+
+    // the number of changed cells with the given contents from |cell| onwards
+    fn dirty_run(&self, Cell(row, col): Cell, contents: Option<(char, Style)>) -> u16 {
+        (col..self.row_width(row))
+            .take_while(|&col| {
+                let cell = Cell(row, col);
+                self.is_dirty(cell) && self.get(cell) == contents && !self.has_link(cell)
+            })
+            .count() as u16
+    }
+
+    // have the cell blanked out on the next present whatever it held before
+    fn invalidate(&mut self, cell: Cell) {
+        let idx = self.index(cell);
+        self.cells.set(idx, None);
+        self.forget_extras(idx);
+        self.dirty[idx] = true;
+    }
+
+    // blank out the cell, returning whether there was anything to blank out
+    fn erase(&mut self, cell: Cell) -> bool {
+        let idx = self.index(cell);
+        let erase = self.cells.is_occupied(idx);
+        if erase {
+            self.cells.set(idx, None);
+            self.forget_extras(idx);
+            self.dirty[idx] = true;
+        }
+        erase
+    }
+
+    // a character taking up multiple screen columns is represented in the buffer
+    // by one Some(character) followed by Nones in the additional cells it covers
+    fn update(&mut self, position: Cell, character: char, style: Style) -> bool {
+        self.update_cluster(position, character.encode_utf8(&mut [0; 4]), style)
+    }
+
+    // a grapheme cluster of several characters, like an emoji ZWJ sequence, is
+    // held by a single cell and takes up the width of the whole cluster
+    fn update_cluster(&mut self, position: Cell, cluster: &str, style: Style) -> bool {
+        self.update_with_width(position, cluster, None, None, style)
+    }
+
+    // |given_width| overrides the width of the cluster when there is one, and
+    // |link| has it be part of a hyperlink to that URL
+    fn update_with_width(
+        &mut self,
+        position: Cell,
+        cluster: &str,
+        given_width: Option<usize>,
+        link: Option<&str>,
+        style: Style,
+    ) -> bool {
+        let mut characters = cluster.chars();
+        let character = match characters.next() {
+            Some(character) => character,
+            None => return false,
+        };
+        let tail = characters.as_str();
+        let (character, given_width, width) = match (tail, given_width) {
+            ("", None) => self.place(character),
+            (_, Some(width)) => (character, given_width, width),
+            _ => (
+                character,
+                None,
+                str_width(cluster, self.ambiguous_wide, self.width_mode),
+            ),
+        };
+        let cell = Some((character, style));
+        let idx = self.index(position);
+        let buffer_size = self.cells.len();
+        if idx >= buffer_size {
+            return false; // e.g. when the terminal has no rows or columns
+        }
+        let Cell(row, col) = position;
+        if self.line_size(row) != LineSize::Normal
+            && col as usize + width > self.row_width(row) as usize
+        {
+            return false; // beyond the half of a double width row
+        }
+        let nones = || (1..width).map(|i| idx + i).filter(|i| *i < buffer_size);
+        // as with given widths, the tail is only looked up if there are any
+        let tail_differs =
+            (!tail.is_empty() || !self.tails.is_empty()) && self.tail(position) != tail;
+        let update = self.cells.get(idx) != cell
+            || tail_differs
+            || self.given_width(idx) != given_width
+            || self.link(idx) != link
+            || nones().any(|i| self.cells.is_occupied(i));
+        if update {
+            self.cells.set(idx, cell);
+            self.forget_extras(idx);
+            if !tail.is_empty() {
+                self.tails.insert(idx, tail.to_string());
+            }
+            if let Some(width) = given_width {
+                self.widths.insert(idx, width);
+            }
+            if let Some(url) = link {
+                self.links.insert(idx, url.to_string());
+            }
+            self.dirty[idx] = true;
+            for i in nones() {
+                self.cells.set(i, None);
+                self.forget_extras(i);
+                self.dirty[i] = true;
+            }
+        }
+        update
+    }
+
+    // forget the cluster tail, given width and link of the cell at |idx|, if any
+    fn forget_extras(&mut self, idx: usize) {
+        self.tails.remove(&idx);
+        self.widths.remove(&idx);
+        self.links.remove(&idx);
+    }
+
+    // move the rows from |row| down by |n|, the rows moved past the bottom being
+    // dropped and those left behind emptied, as the terminal inserting lines does
+    fn insert_rows(&mut self, row: u16, n: u16) {
+        let start = self.index(Cell(row, 0));
+        let shift = cmp::min(n as usize * self.width as usize, self.cells.len() - start);
+        let len = self.cells.len();
+        self.cells.rotate_right(start..len, shift);
+        self.dirty[start..].rotate_right(shift);
+        for i in start..start + shift {
+            self.cells.set(i, None);
+            self.dirty[i] = false;
+        }
+        self.remap_extras(|idx| match idx {
+            idx if idx < start => Some(idx),
+            idx if idx + shift < len => Some(idx + shift),
+            _ => None,
+        });
+        let rows = (len / cmp::max(self.width as usize, 1)) as u16;
+        self.remap_line_sizes(|line| match line {
+            line if line < row => Some(line),
+            line if line + n < rows => Some(line + n),
+            _ => None,
+        });
+    }
+
+    // move the rows below |row| and the |n| rows from it up by |n|, those from
+    // |row| being dropped and the ones left behind at the bottom emptied, as the
+    // terminal deleting lines does
+    fn delete_rows(&mut self, row: u16, n: u16) {
+        let start = self.index(Cell(row, 0));
+        let shift = cmp::min(n as usize * self.width as usize, self.cells.len() - start);
+        let len = self.cells.len();
+        self.cells.rotate_left(start..len, shift);
+        self.dirty[start..].rotate_left(shift);
+        for i in len - shift..len {
+            self.cells.set(i, None);
+            self.dirty[i] = false;
+        }
+        self.remap_extras(|idx| match idx {
+            idx if idx < start => Some(idx),
+            idx if idx < start + shift => None,
+            idx => Some(idx - shift),
+        });
+        self.remap_line_sizes(|line| match line {
+            line if line < row => Some(line),
+            line if line < row + n => None,
+            line => Some(line - n),
+        });
+    }
+
+    // move the line sizes to the rows |remap| has for them, as the lines are
+    // moved along with what's on them, dropping those it has none for
+    fn remap_line_sizes<F>(&mut self, remap: F)
+    where
+        F: Fn(u16) -> Option<u16>,
+    {
+        self.line_sizes = (self.line_sizes.drain())
+            .filter_map(|(row, size)| remap(row).map(|row| (row, size)))
+            .collect();
+    }
+
+    // move the cluster tails, given widths and links to the indices |remap| has
+    // for them, dropping those it has none for
+    fn remap_extras<F>(&mut self, remap: F)
+    where
+        F: Fn(usize) -> Option<usize>,
+    {
+        self.tails = (self.tails.drain())
+            .filter_map(|(idx, tail)| remap(idx).map(|idx| (idx, tail)))
+            .collect();
+        self.widths = (self.widths.drain())
+            .filter_map(|(idx, width)| remap(idx).map(|idx| (idx, width)))
+            .collect();
+        self.links = (self.links.drain())
+            .filter_map(|(idx, url)| remap(idx).map(|idx| (idx, url)))
+            .collect();
+    }
+
+    // move the cells from |cell| to the end of its row right by |n|, the cells
+    // moved past the edge being dropped and those left behind emptied, as the
+    // terminal inserting characters does
+    fn insert_cells(&mut self, cell: Cell, n: u16) {
+        let Cell(row, _) = cell;
+        let (start, end) = (self.index(cell), self.index(Cell(row, self.width)));
+        let shift = cmp::min(n as usize, end - start);
+        self.cells.rotate_right(start..end, shift);
+        self.dirty[start..end].rotate_right(shift);
+        for i in start..start + shift {
+            self.cells.set(i, None);
+            self.dirty[i] = false;
+        }
+        self.remap_extras(|idx| match idx {
+            idx if idx < start || idx >= end => Some(idx),
+            idx if idx + shift < end => Some(idx + shift),
+            _ => None,
+        });
+    }
+
+    // move the cells after the |n| from |cell| to the end of its row left by
+    // |n|, those from |cell| being dropped and the ones left behind at the end
+    // emptied, as the terminal deleting characters does
+    fn delete_cells(&mut self, cell: Cell, n: u16) {
+        let Cell(row, _) = cell;
+        let (start, end) = (self.index(cell), self.index(Cell(row, self.width)));
+        let shift = cmp::min(n as usize, end - start);
+        self.cells.rotate_left(start..end, shift);
+        self.dirty[start..end].rotate_left(shift);
+        for i in end - shift..end {
+            self.cells.set(i, None);
+            self.dirty[i] = false;
+        }
+        self.remap_extras(|idx| match idx {
+            idx if idx < start || idx >= end => Some(idx),
+            idx if idx < start + shift => None,
+            idx => Some(idx - shift),
+        });
+    }
+
+    // everything there is to what the cell holds, for putting it back as it was
+    fn contents(&self, cell: Cell) -> Option<CellContents> {
+        self.get(cell).map(|(character, style)| {
+            let cluster = format!("{}{}", character, self.tail(cell));
+            let idx = self.index(cell);
+            let link = self.link(idx).map(|url| url.to_string());
+            (cluster, style, self.widths.get(&idx).copied(), link)
+        })
+    }
+
+    // copy the |len| cells from |from| over those from |to|, on another row; a
+    // wide character that doesn't fit within them is left out
+    fn copy_cells(&mut self, Cell(from_row, from_col): Cell, Cell(to_row, to_col): Cell, len: u16) {
+        for i in 0..len {
+            let (from, to) = (Cell(from_row, from_col + i), Cell(to_row, to_col + i));
+            match self.contents(from) {
+                Some((cluster, style, given_width, link)) if i + self.span(from) <= len => {
+                    self.update_with_width(to, &cluster, given_width, link.as_deref(), style);
+                }
+                _ => {
+                    self.erase(to);
+                }
+            }
+        }
+    }
+
+    // the cell holding the wide character that |cell| is one of the columns
+    // after the first of, if it is
+    fn covering(&self, cell: Cell) -> Option<Cell> {
+        match (self.get(cell), cell) {
+            (None, Cell(row, col)) if col > 0 => {
+                let left = Cell(row, col - 1);
+                Some(left).filter(|&left| self.get(left).is_some() && self.span(left) > 1)
+            }
+            _ => None,
+        }
+    }
+
+    // give what the cell holds the style |style|
+    fn set_style(&mut self, cell: Cell, style: Style) {
+        let idx = self.index(cell);
+        if let Some((character, old)) = self.cells.get(idx) {
+            if old != style {
+                self.cells.set(idx, Some((character, style)));
+                self.dirty[idx] = true;
+            }
+        }
+    }
+}
+
+/*
+ * TermProfile holds the sequences used for controlling the terminal, so that
+ * they can be tailored to terminals that don't understand the ANSI defaults.
+ * The parameterized ones are terminfo capabilities, which are left out to have
+ * the ANSI sequences formatted instead.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct TermProfile {
+    pub enter_altscreen: String,
+    pub leave_altscreen: String,
+    pub hide_cursor: String,
+    pub show_cursor: String,
+    pub clear: String,
+    pub erase_line: String,
+    pub erase_below: String,
+    pub cursor_address: Option<String>,
+    pub set_fg: Option<String>,
+    pub set_bg: Option<String>,
+}
+
+impl Default for TermProfile {
+    fn default() -> TermProfile {
+        TermProfile {
+            enter_altscreen: "\x1B7\x1B[?47h".to_string(),
+            leave_altscreen: "\x1B[?47l\x1B8".to_string(),
+            hide_cursor: "\x1B[?25l".to_string(),
+            show_cursor: "\x1B[?25h".to_string(),
+            clear: "\x1B[2J".to_string(),
+            erase_line: "\x1B[0K".to_string(),
+            erase_below: "\x1B[0J".to_string(),
+            cursor_address: None,
+            set_fg: None,
+            set_bg: None,
+        }
+    }
+}
+
+#[cfg_attr(test, allow(dead_code))] // tests don't make use of all these
+impl TermProfile {
+    // the sequences terminfo has for the terminal in TERM, falling back to the
+    // defaults for any it's missing
+    pub fn from_terminfo() -> TermProfile {
+        let mut profile = TermProfile::default();
+        if let Ok(info) = term::terminfo::TermInfo::from_env() {
+            let capabilities = [
+                ("smcup", &mut profile.enter_altscreen),
+                ("rmcup", &mut profile.leave_altscreen),
+                ("civis", &mut profile.hide_cursor),
+                ("cnorm", &mut profile.show_cursor),
+                ("clear", &mut profile.clear),
+                ("el", &mut profile.erase_line),
+                ("ed", &mut profile.erase_below),
+            ];
+            for (name, sequence) in capabilities {
+                if let Some(bytes) = info.strings.get(name) {
+                    *sequence = without_padding(&String::from_utf8_lossy(bytes));
+                }
+            }
+            let capability = |name| {
+                (info.strings.get(name))
+                    .map(|bytes| without_padding(&String::from_utf8_lossy(bytes)))
+            };
+            profile.cursor_address = capability("cup");
+            profile.set_fg = capability("setaf");
+            profile.set_bg = capability("setab");
+        }
+        profile
+    }
+}
+
+// |capability| with |params| filled in, or None if terminfo can't make sense
+// of it
+fn expand(capability: &str, params: &[i32]) -> Option<String> {
+    use term::terminfo::parm::{self, Param, Variables};
+    let params: Vec<Param> = params.iter().map(|&param| Param::Number(param)).collect();
+    let expanded = parm::expand(capability.as_bytes(), &params, &mut Variables::new()).ok()?;
+    Some(String::from_utf8_lossy(&expanded).into_owned())
+}
+
+// |sequence| without the $<..> delays terminfo may have in it, which are for
+// the terminal library to act on rather than for the terminal
+fn without_padding(sequence: &str) -> String {
+    let mut result = String::new();
+    let mut rest = sequence;
+    while let Some(start) = rest.find("$<") {
+        result += &rest[..start];
+        rest = match rest[start..].find('>') {
+            Some(end) => &rest[start + end + 1..],
+            None => "",
+        };
+    }
+    result + rest
+}
+
+/*
+ * Terminal is a simple wrapper that provides some helpful methods for common
+ * ouput operations.
+ */
+struct Terminal {
+    out: BufferedWriter,
+    // whether the output is a terminal rather than e.g. a pipe or a file
+    tty: bool,
+    // whether erasing fills with the current background color (back color erase)
+    bce: bool,
+    // the colors last sent, None when unknown
+    last_fg: Option<Color>,
+    last_bg: Option<Color>,
+    // the attributes last sent
+    attrs: Attrs,
+    // where the cursor is, None when unknown
+    cursor: Option<Cell>,
+    // the keyboard flags pushed and not yet popped, the last pushed on top
+    keyboard_flags: Vec<KeyboardFlags>,
+    // the keyboard flags popped by hand_back, for reapply to push again
+    handed_back_keyboard_flags: Vec<KeyboardFlags>,
+    // whether the title from before we set ours is saved on the title stack
+    title_pushed: bool,
+    // the title last set, for reapply to set again
+    title: Option<String>,
+    // whether characters of ambiguous width take up two columns
+    ambiguous_wide: bool,
+    // where the widths of characters are looked up
+    width_mode: WidthMode,
+    // whether the terminal can't do colors or cursor movement, like with
+    // TERM=dumb, so that only characters and newlines are written
+    plain: bool,
+    // whether the alternate screen is in use, as far as we know
+    altscreen: bool,
+    // whether the cursor was last shown rather than hidden
+    cursor_visible: bool,
+    // the cursor shape and whether it blinks, None where left to the terminal
+    cursor_shape: Option<CursorShape>,
+    cursor_blink: Option<bool>,
+    // the URL of the hyperlink being written, if any
+    link: Option<String>,
+    // the sequences for setting up the terminal and restoring it
+    profile: TermProfile,
+}
+
+#[cfg_attr(test, allow(dead_code))] // tests don't make use of all these
+impl Terminal {
+    #[cfg(not(test))]
+    pub fn new() -> Option<Terminal> {
+        if Terminal::is_dumb() {
+            return Some(Terminal::plain(Box::new(io::stdout())));
+        }
+        term::stdout().map(|terminal| {
+            let mut terminal = Terminal::with_writer(Box::new(terminal));
+            terminal.tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+            terminal.bce = term::terminfo::TermInfo::from_env()
+                .map(|info| info.bools.get("bce") == Some(&true))
+                .unwrap_or(false);
+            terminal
+        })
+    }
+
+    pub fn with_writer(out: Box<dyn Write>) -> Terminal {
+        Terminal {
+            out: BufferedWriter::new(out),
+            tty: true,
+            bce: false,
+            last_fg: None,
+            last_bg: None,
+            attrs: Attrs::empty(),
+            cursor: None,
+            keyboard_flags: Vec::new(),
+            handed_back_keyboard_flags: Vec::new(),
+            title_pushed: false,
+            title: None,
+            ambiguous_wide: false,
+            width_mode: WidthMode::UnicodeWidth,
+            plain: false,
+            altscreen: false,
+            cursor_visible: true,
+            cursor_shape: None,
+            cursor_blink: None,
+            link: None,
+            profile: TermProfile::default(),
+        }
+    }
+
+    // a terminal that's only ever written characters and newlines to, and is
+    // otherwise treated like output that's not a terminal at all
+    pub fn plain(out: Box<dyn Write>) -> Terminal {
+        let mut terminal = Terminal::with_writer(out);
+        terminal.tty = false;
+        terminal.plain = true;
+        terminal
+    }
+
+    // whether TERM is unset or says the terminal is dumb, in which case escape
+    // sequences would only show up as junk
+    pub fn is_dumb() -> bool {
+        dumb_term(std::env::var("TERM").ok().as_deref())
+    }
+
+    pub fn is_tty(&self) -> bool {
+        self.tty
+    }
+
+    pub fn set_style(&mut self, style: Style) {
+        self.set_attrs(style.attrs);
+        self.set_fg(style.fg);
+        self.set_bg(style.bg);
+    }
+
+    // attributes are only turned off by resetting everything, colors included
+    pub fn set_attrs(&mut self, attrs: Attrs) {
+        if self.attrs != attrs && !self.plain {
+            let mut sgr = "\x1B[0".to_string();
+            for &(attr, param) in &SGR_ATTRS {
+                if attrs.contains(attr) {
+                    sgr += &format!(";{}", param);
+                }
+            }
+            (write!(self.out, "{}m", sgr)).unwrap();
+            self.attrs = attrs;
+            self.forget_colors();
+        }
+    }
+
+    pub fn set_fg(&mut self, fg: Color) {
+        if self.last_fg != Some(fg) && !self.plain {
+            let sequence = color_sequence(self.profile.set_fg.as_deref(), 30, fg);
+            (write!(self.out, "{}", sequence)).unwrap();
+            self.last_fg = Some(fg);
+        }
+    }
+
+    pub fn set_bg(&mut self, bg: Color) {
+        if self.last_bg != Some(bg) && !self.plain {
+            let sequence = color_sequence(self.profile.set_bg.as_deref(), 40, bg);
+            (write!(self.out, "{}", sequence)).unwrap();
+            self.last_bg = Some(bg);
+        }
+    }
+
+    // reset the attributes and colors whatever they're thought to be, as they
+    // may have been left set by something else, like a program run before
+    pub fn reset_attributes(&mut self) {
+        if !self.plain {
+            (write!(self.out, "\x1B[0m")).unwrap();
+            self.attrs = Attrs::empty();
+            self.forget_colors();
+        }
+    }
+
+    // forget the colors last sent, for after the terminal may have changed them
+    fn forget_colors(&mut self) {
+        self.last_fg = None;
+        self.last_bg = None;
+    }
+
+    // a plain terminal has nothing to clear, its output simply carries on, and
+    // as some clear sequences also home the cursor where it is isn't known after
+    pub fn clear(&mut self) {
+        if !self.plain {
+            self.reset_attributes();
+            (write!(self.out, "{}", self.profile.clear)).unwrap();
+            self.forget_cursor();
+        }
+    }
+
+    // erase from the cursor to the end of the line
+    pub fn erase_line(&mut self) {
+        if !self.plain {
+            (write!(self.out, "{}", self.profile.erase_line)).unwrap();
+        }
+    }
+
+    // erase from the cursor to the end of the screen
+    #[allow(dead_code)] // not used by the editor yet
+    pub fn erase_below(&mut self) {
+        if !self.plain {
+            (write!(self.out, "{}", self.profile.erase_below)).unwrap();
+        }
+    }
+
+    // insert |n| blank lines at the cursor's row, pushing the rest down
+    pub fn insert_lines(&mut self, n: u16) {
+        if !self.plain {
+            (write!(self.out, "\x1B[{}L", n)).unwrap();
+        }
+    }
+
+    // delete |n| lines from the cursor's row, pulling the rest up
+    pub fn delete_lines(&mut self, n: u16) {
+        if !self.plain {
+            (write!(self.out, "\x1B[{}M", n)).unwrap();
+        }
+    }
+
+    // insert |n| blank characters at the cursor, pushing the rest of the row right
+    pub fn insert_chars(&mut self, n: u16) {
+        if !self.plain {
+            (write!(self.out, "\x1B[{}@", n)).unwrap();
+        }
+    }
+
+    // delete |n| characters from the cursor, pulling the rest of the row left
+    pub fn delete_chars(&mut self, n: u16) {
+        if !self.plain {
+            (write!(self.out, "\x1B[{}P", n)).unwrap();
+        }
+    }
+
+    // use the terminal's default colors, which is what a clear leaves behind
+    pub fn set_default_colors(&mut self) {
+        if !self.plain {
+            self.set_attrs(Attrs::empty());
+            (write!(self.out, "\x1B[39;49m")).unwrap();
+            self.forget_colors();
+        }
+    }
+
+    // the cursor is saved going in, so entering twice would lose where it was
+    // on the normal screen
+    pub fn enable_altscreen(&mut self) {
+        if !self.altscreen {
+            (write!(self.out, "{}", self.profile.enter_altscreen)).unwrap();
+            self.altscreen = true;
+            self.forget_colors();
+            self.forget_cursor();
+        }
+    }
+
+    // restoring the saved cursor restores the attributes saved along with it
+    pub fn disable_altscreen(&mut self) {
+        if self.altscreen {
+            (write!(self.out, "{}", self.profile.leave_altscreen)).unwrap();
+            self.altscreen = false;
+            self.attrs = Attrs::empty();
+            self.forget_colors();
+            self.forget_cursor();
+        }
+    }
+
+    // the cursor is hidden and shown regardless of whether it already is, as
+    // something else may have shown it in the meantime, like the shell while
+    // suspended
+    pub fn hide_cursor(&mut self) {
+        (write!(self.out, "{}", self.profile.hide_cursor)).unwrap();
+        self.cursor_visible = false;
+    }
+
+    pub fn show_cursor(&mut self) {
+        (write!(self.out, "{}", self.profile.show_cursor)).unwrap();
+        self.cursor_visible = true;
+    }
+
+    // have the terminal send FOCUS_GAINED and FOCUS_LOST as it gains and loses
+    // focus, to be picked up along with the rest of the input
+    pub fn enable_focus_reporting(&mut self) {
+        (write!(self.out, "\x1B[?1004h")).unwrap();
+    }
+
+    pub fn disable_focus_reporting(&mut self) {
+        (write!(self.out, "\x1B[?1004l")).unwrap();
+    }
+
+    // move the cursor by whichever is shorter of an absolute or a relative move,
+    // or on a plain terminal by going down a line at a time, if at all, and
+    // padding with spaces, there being no going back up or left other than to
+    // carry on on a new line
+    pub fn set_cursor_position(&mut self, row: u16, col: u16) {
+        let target = Cell(row, col);
+        if self.plain && self.cursor != Some(target) {
+            let (lines, start) = match self.cursor {
+                Some(Cell(cursor_row, cursor_col)) if cursor_row == row && cursor_col < col => {
+                    (0, cursor_col)
+                }
+                Some(Cell(cursor_row, _)) if cursor_row < row => (row - cursor_row, 0),
+                _ => (1, 0),
+            };
+            let newlines = "\r\n".repeat(lines as usize);
+            let padding = " ".repeat((col - start) as usize);
+            (write!(self.out, "{}{}", newlines, padding)).unwrap();
+            self.cursor = Some(target);
+        } else if self.cursor != Some(target) {
+            // add (1, 1) becase terminal row/col is one-indexed
+            let ansi = format!("\x1B[{};{}H", row + 1, col + 1);
+            let absolute = (self.profile.cursor_address.as_deref())
+                .and_then(|cup| expand(cup, &[row as i32, col as i32]))
+                .unwrap_or_else(|| ansi.clone());
+            // the relative moves are ANSI too, so they're only for terminals
+            // addressing the cursor the ANSI way
+            let movement = match self.cursor.map(|cursor| relative_move(cursor, target)) {
+                Some(relative) if relative.len() < absolute.len() && absolute == ansi => relative,
+                _ => absolute,
+            };
+            (write!(self.out, "{}", movement)).unwrap();
+            self.cursor = Some(target);
+        }
+    }
+
+    pub fn forget_cursor(&mut self) {
+        self.cursor = None;
+    }
+
+    // set the size of the line the cursor is on
+    pub fn set_line_size(&mut self, size: LineSize) {
+        if !self.plain {
+            let param = match size {
+                LineSize::Normal => 5,
+                LineSize::DoubleWidth => 6,
+                LineSize::DoubleHeightTop => 3,
+                LineSize::DoubleHeightBottom => 4,
+            };
+            (write!(self.out, "\x1B#{}", param)).unwrap();
+        }
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.cursor_shape = Some(shape);
+        self.set_cursor_style();
+    }
+
+    // have the cursor blink or hold steady, keeping its shape
+    pub fn set_cursor_blink(&mut self, blink: bool) {
+        self.cursor_blink = Some(blink);
+        self.set_cursor_style();
+    }
+
+    // the shape and blinking are set together, the terminal's default being a
+    // blinking block where either is left to it
+    fn set_cursor_style(&mut self) {
+        if !self.plain {
+            let param = match self.cursor_shape.unwrap_or(CursorShape::Block) {
+                CursorShape::Block => 1,
+                CursorShape::Underline => 3,
+                CursorShape::Bar => 5,
+            };
+            let steady = self.cursor_blink == Some(false);
+            (write!(self.out, "\x1B[{} q", param + steady as u8)).unwrap();
+        }
+    }
+
+    // leave the cursor shape and blinking to the terminal again
+    pub fn reset_cursor_style(&mut self) {
+        if self.cursor_shape.is_some() || self.cursor_blink.is_some() {
+            (write!(self.out, "\x1B[0 q")).unwrap();
+            self.cursor_shape = None;
+            self.cursor_blink = None;
+        }
+    }
+
+    // the SCO sequences are used rather than DECSC and DECRC, which would
+    // clobber the cursor saved when entering the alternate screen
+    pub fn save_cursor(&mut self) {
+        if !self.plain {
+            (write!(self.out, "\x1B[s")).unwrap();
+        }
+    }
+
+    // where the cursor ends up isn't tracked, so it's forgotten
+    pub fn restore_cursor(&mut self) {
+        if !self.plain {
+            (write!(self.out, "\x1B[u")).unwrap();
+        }
+        self.forget_cursor();
+    }
+
+    // the cursor advances past the character, which the caller must forget about
+    // if that takes it to the edge of the screen
+    pub fn put(&mut self, character: char) {
+        (write!(self.out, "{}", character)).unwrap();
+        let width = char_width(character, self.ambiguous_wide, self.width_mode);
+        let width = width.unwrap_or(0) as u16;
+        self.cursor = self.cursor.map(|cursor| cursor + Cell(0, width));
+    }
+
+    pub fn put_str(&mut self, text: &str) {
+        (write!(self.out, "{}", text)).unwrap();
+        let width = str_width(text, self.ambiguous_wide, self.width_mode) as u16;
+        self.cursor = self.cursor.map(|cursor| cursor + Cell(0, width));
+    }
+
+    // what's written from now on is part of a hyperlink to |url|, or of none
+    pub fn set_link(&mut self, url: Option<&str>) {
+        if self.link.as_deref() != url && !self.plain {
+            (write!(self.out, "\x1B]8;;{}\x07", url.unwrap_or(""))).unwrap();
+            self.link = url.map(|url| url.to_string());
+        }
+    }
+
+    // control characters are left out, lest they end the sequence early and
+    // have the rest of the title interpreted by the terminal
+    pub fn set_title(&mut self, title: &str) {
+        if !self.title_pushed {
+            (write!(self.out, "\x1B[22;2t")).unwrap();
+            self.title_pushed = true;
+        }
+        let title: String = title.chars().filter(|c| !c.is_control()).collect();
+        (write!(self.out, "\x1B]0;{}\x07", title)).unwrap();
+        self.title = Some(title);
+    }
+
+    // bring back the title from before set_title, if it was ever called
+    pub fn restore_title(&mut self) {
+        if self.title_pushed {
+            (write!(self.out, "\x1B[23;2t")).unwrap();
+            self.title_pushed = false;
+        }
+    }
+
+    // text too long for terminals to take is cut short, returning false
+    pub fn set_clipboard(&mut self, text: &str) -> bool {
+        let mut len = cmp::min(text.len(), MAX_CLIPBOARD_PAYLOAD / 4 * 3);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let payload = base64(&text.as_bytes()[..len]);
+        (write!(self.out, "\x1B]52;c;{}\x07", payload)).unwrap();
+        len == text.len()
+    }
+
+    pub fn push_keyboard_flags(&mut self, flags: KeyboardFlags) {
+        (write!(self.out, "\x1B[>{}u", flags.bits())).unwrap();
+        self.keyboard_flags.push(flags);
+    }
+
+    pub fn pop_keyboard_flags(&mut self) {
+        if self.keyboard_flags.pop().is_some() {
+            (write!(self.out, "\x1B[<u")).unwrap();
+        }
+    }
+
+    pub fn pop_all_keyboard_flags(&mut self) {
+        if !self.keyboard_flags.is_empty() {
+            (write!(self.out, "\x1B[<{}u", self.keyboard_flags.len())).unwrap();
+            self.keyboard_flags.clear();
+        }
+    }
+
+    // what undoes everything the terminal's been set up with, short of clearing
+    // the alternate screen, as written by hand_back and by job_control when
+    // stopped
+    pub fn hand_back_sequence(&self) -> String {
+        let mut sequence = String::new();
+        // popped while still on the screen they were pushed on
+        if !self.keyboard_flags.is_empty() {
+            sequence += &format!("\x1B[<{}u", self.keyboard_flags.len());
+        }
+        if self.title_pushed {
+            sequence += "\x1B[23;2t";
+        }
+        sequence += "\x1B[?1004l";
+        sequence += &self.profile.show_cursor;
+        if self.cursor_shape.is_some() || self.cursor_blink.is_some() {
+            sequence += "\x1B[0 q";
+        }
+        if self.altscreen {
+            sequence += &self.profile.leave_altscreen;
+        }
+        sequence
+    }
+
+    // hand the terminal back the way it was before being set up, remembering
+    // the keyboard flags, title and cursor style for reapply
+    pub fn hand_back(&mut self) {
+        (write!(self.out, "{}", self.hand_back_sequence())).unwrap();
+        self.handed_back_keyboard_flags = mem::take(&mut self.keyboard_flags);
+        self.title_pushed = false;
+        self.cursor_visible = true;
+        if self.altscreen {
+            self.altscreen = false;
+            self.attrs = Attrs::empty();
+        }
+        self.forget_colors();
+        self.forget_cursor();
+    }
+
+    // set the terminal up again after hand_back, or after it was handed back
+    // by job_control, in which case what was set up is still thought to be
+    pub fn reapply(&mut self) {
+        // which may well have left the alternate screen
+        self.altscreen = false;
+        self.enable_altscreen();
+        self.hide_cursor();
+        self.enable_focus_reporting();
+        let mut keyboard_flags = mem::take(&mut self.keyboard_flags);
+        keyboard_flags.append(&mut self.handed_back_keyboard_flags);
+        for flags in keyboard_flags {
+            self.push_keyboard_flags(flags);
+        }
+        if let Some(title) = self.title.take() {
+            self.title_pushed = false;
+            self.set_title(&title);
+        }
+        if self.cursor_shape.is_some() || self.cursor_blink.is_some() {
+            self.set_cursor_style();
+        }
+    }
+
+    // follow up with a primary device attributes query, which every terminal
+    // answers, so that an unsupported query doesn't leave anyone waiting
+    pub fn query_keyboard_flags(&mut self) {
+        (write!(self.out, "\x1B[?u\x1B[c")).unwrap();
+    }
+
+    // followed up with a device attributes query for the same reason as above
+    pub fn query_synchronized_output(&mut self) {
+        (write!(self.out, "\x1B[?2026$p\x1B[c")).unwrap();
+    }
+
+    pub fn flush(&mut self) {
+        self.out.flush().unwrap();
+    }
+
+    // the number of bytes that reached the output since last asked
+    pub fn take_bytes_written(&mut self) -> usize {
+        std::mem::replace(&mut self.out.written, 0)
+    }
+}
+
+/*
+ * Keyboard flags enable the progressive enhancements of the kitty keyboard
+ * protocol, as also supported by e.g. iTerm2, foot and WezTerm.
+ */
+bitflags::bitflags! {
+    pub struct KeyboardFlags: u8 {
+        const DISAMBIGUATE_ESCAPE_CODES = 1 << 0;
+        const REPORT_EVENT_TYPES        = 1 << 1;
+        const REPORT_ALTERNATE_KEYS     = 1 << 2;
+        const REPORT_ALL_KEYS_AS_ESCAPE = 1 << 3;
+        const REPORT_ASSOCIATED_TEXT    = 1 << 4;
+    }
+}
+
+// the keyboard flags in |reply| to a keyboard flags query, or None if the reply
+// holds only the device attributes, meaning the protocol isn't supported
+#[allow(dead_code)] // the input side doesn't probe for the protocol yet
+pub fn parse_keyboard_flags(reply: &[u8]) -> Option<KeyboardFlags> {
+    let start = reply.windows(3).position(|w| w == b"\x1B[?")? + 3;
+    let digits = reply[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if reply.get(start + digits) != Some(&b'u') {
+        // the device attributes came first, so there's no flags reply at all
+        return None;
+    }
+    std::str::from_utf8(&reply[start..start + digits])
+        .ok()?
+        .parse()
+        .ok()
+        .map(KeyboardFlags::from_bits_truncate)
+}
+
+// whether |reply| to a synchronized output query says the mode is supported,
+// which it doesn't if it holds only the device attributes
+#[allow(dead_code)] // the input side doesn't probe for synchronized output yet
+pub fn parse_synchronized_output(reply: &[u8]) -> bool {
+    const REPORT: &[u8] = b"\x1B[?2026;";
+    reply
+        .windows(REPORT.len() + 3)
+        .any(|w| w.starts_with(REPORT) && w.ends_with(b"$y") && b"123".contains(&w[REPORT.len()]))
+}
+
+// what begins and ends a synchronized update, see set_synchronized_output
+const BEGIN_SYNCHRONIZED_UPDATE: &[u8] = b"\x1B[?2026h";
+const END_SYNCHRONIZED_UPDATE: &[u8] = b"\x1B[?2026l";
+
+/*
+ * BufferedWriter holds on to output until flushed, or until more than the
+ * threshold number of bytes have piled up if there is one. Output between
+ * flushes makes up a frame, which when synchronized goes out as a single
+ * synchronized update however many times the threshold is crossed.
+ */
+struct BufferedWriter {
+    writer: Box<dyn Write>,
+    buffer: Vec<u8>,
+    threshold: Option<usize>,
+    // whether to have the terminal present each frame all at once
+    synchronized: bool,
+    // whether a synchronized update has begun that's yet to end
+    updating: bool,
+    // the number of bytes handed on to the writer
+    written: usize,
+}
+
+impl BufferedWriter {
+    fn new(writer: Box<dyn Write>) -> BufferedWriter {
+        BufferedWriter {
+            writer,
+            buffer: Vec::new(),
+            threshold: None,
+            synchronized: false,
+            updating: false,
+            written: 0,
+        }
+    }
+
+    // hand what's piled up on to the writer, leaving the frame going
+    fn write_buffer(&mut self) -> io::Result<()> {
+        self.writer.write_all(&self.buffer)?;
+        self.written += self.buffer.len();
+        self.buffer.clear();
+        self.writer.flush()
+    }
+}
+
+impl Write for BufferedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.synchronized && !self.updating && !buf.is_empty() {
+            self.buffer.extend_from_slice(BEGIN_SYNCHRONIZED_UPDATE);
+            self.updating = true;
+        }
+        self.buffer.extend_from_slice(buf);
+        if matches!(self.threshold, Some(bytes) if self.buffer.len() > bytes) {
+            self.write_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    // end the frame
+    fn flush(&mut self) -> io::Result<()> {
+        if self.updating {
+            self.buffer.extend_from_slice(END_SYNCHRONIZED_UPDATE);
+            self.updating = false;
+        }
+        self.write_buffer()
+    }
+}
+
+impl Drop for BufferedWriter {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+// whether |term|, the value of TERM if set, leaves the terminal to be written
+// nothing but characters
+fn dumb_term(term: Option<&str>) -> bool {
+    matches!(term, None | Some("") | Some("dumb"))
+}
+
+// the sequence moving the cursor from |from| to |to| relative to where it is
+fn relative_move(Cell(from_row, from_col): Cell, Cell(to_row, to_col): Cell) -> String {
+    let vertical = match to_row.cmp(&from_row) {
+        cmp::Ordering::Less => format!("\x1B[{}A", from_row - to_row),
+        cmp::Ordering::Greater => format!("\x1B[{}B", to_row - from_row),
+        cmp::Ordering::Equal => String::new(),
+    };
+    let horizontal = match to_col.cmp(&from_col) {
+        cmp::Ordering::Less if to_col == 0 => "\r".to_string(),
+        cmp::Ordering::Less => format!("\x1B[{}D", from_col - to_col),
+        cmp::Ordering::Greater => format!("\x1B[{}C", to_col - from_col),
+        cmp::Ordering::Equal => String::new(),
+    };
+    vertical + &horizontal
+}
+
+// base64 encoded payloads much larger than this tend to be dropped by terminals
+const MAX_CLIPBOARD_PAYLOAD: usize = 100_000;
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len() / 3 * 4 + 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0, |group, (i, &byte)| {
+            group | (byte as usize) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i)) & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// the SGR parameters turning on attributes
+const SGR_ATTRS: [(Attrs, u32); 5] = [
+    (Attrs::BOLD, 1),
+    (Attrs::DIM, 2),
+    (Attrs::ITALIC, 3),
+    (Attrs::UNDERLINE, 4),
+    (Attrs::REVERSE, 7),
+];
+
+// the sequence setting |color|, using the terminfo |capability| if there is
+// one and SGR parameters based on |base| otherwise, which RGB colors always use
+fn color_sequence(capability: Option<&str>, base: u32, color: Color) -> String {
+    let expanded = match color {
+        Color::Rgb(..) => None,
+        _ => capability.and_then(|cap| expand(cap, &[color.to_term_color() as i32])),
+    };
+    expanded.unwrap_or_else(|| format!("\x1B[{}m", sgr_color(base, color)))
+}
+
+// the SGR parameters selecting |color|, |base| being 30 for fg and 40 for bg
+fn sgr_color(base: u32, color: Color) -> String {
+    match color {
+        Color::Indexed(index) => format!("{};5;{}", base + 8, index),
+        Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
+        named => match named.to_term_color() {
+            normal @ 0..=7 => (base + normal).to_string(),
+            bright => (base + 60 + bright - 8).to_string(),
+        },
+    }
+}
+
+/*
+ * Color values for terminal output.
+ */
+#[allow(dead_code)] // colors are not used much yet
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    BrightBlack,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+    // one of the 256 colors of the terminal's palette
+    Indexed(u8),
+    // a 24-bit color, for terminals supporting those
+    Rgb(u8, u8, u8),
+}
+
+// the names colors go by in e.g. config files
+const COLOR_NAMES: [(&str, Color); 16] = [
+    ("black", Color::Black),
+    ("red", Color::Red),
+    ("green", Color::Green),
+    ("yellow", Color::Yellow),
+    ("blue", Color::Blue),
+    ("magenta", Color::Magenta),
+    ("cyan", Color::Cyan),
+    ("white", Color::White),
+    ("bright_black", Color::BrightBlack),
+    ("bright_red", Color::BrightRed),
+    ("bright_green", Color::BrightGreen),
+    ("bright_yellow", Color::BrightYellow),
+    ("bright_blue", Color::BrightBlue),
+    ("bright_magenta", Color::BrightMagenta),
+    ("bright_cyan", Color::BrightCyan),
+    ("bright_white", Color::BrightWhite),
+];
+
+#[allow(dead_code)] // colors are not used much yet
+impl Color {
+    pub fn to_term_color(&self) -> term::color::Color {
+        match *self {
+            Color::Black => term::color::BLACK,
+            Color::Red => term::color::RED,
+            Color::Green => term::color::GREEN,
+            Color::Yellow => term::color::YELLOW,
+            Color::Blue => term::color::BLUE,
+            Color::Magenta => term::color::MAGENTA,
+            Color::Cyan => term::color::CYAN,
+            Color::White => term::color::WHITE,
+            Color::BrightBlack => term::color::BRIGHT_BLACK,
+            Color::BrightRed => term::color::BRIGHT_RED,
+            Color::BrightGreen => term::color::BRIGHT_GREEN,
+            Color::BrightYellow => term::color::BRIGHT_YELLOW,
+            Color::BrightBlue => term::color::BRIGHT_BLUE,
+            Color::BrightMagenta => term::color::BRIGHT_MAGENTA,
+            Color::BrightCyan => term::color::BRIGHT_CYAN,
+            Color::BrightWhite => term::color::BRIGHT_WHITE,
+            Color::Indexed(index) => index as term::color::Color,
+            // the closest match in the palette's 6x6x6 color cube
+            Color::Rgb(r, g, b) => {
+                let level = |value: u8| match value {
+                    0..=47 => 0,
+                    48..=114 => 1,
+                    _ => (value as term::color::Color - 35) / 40,
+                };
+                16 + 36 * level(r) + 6 * level(g) + level(b)
+            }
+        }
+    }
+
+    // the red, green and blue components of the color, assuming the standard
+    // xterm palette for named and indexed colors
+    pub fn to_rgb(self) -> (u8, u8, u8) {
+        let index = match self {
+            Color::Rgb(r, g, b) => return (r, g, b),
+            Color::Indexed(index) => index,
+            named => named.to_term_color() as u8,
+        };
+        match index {
+            0..=15 => ANSI_RGB[index as usize],
+            16..=231 => {
+                let level = |value: u8| if value == 0 { 0 } else { 55 + 40 * value };
+                let index = index - 16;
+                (level(index / 36), level(index / 6 % 6), level(index % 6))
+            }
+            _ => {
+                let gray = 8 + 10 * (index - 232);
+                (gray, gray, gray)
+            }
+        }
+    }
+
+    // the color |t| of the way from this color to |other|
+    pub fn blend(self, other: Color, t: f32) -> Color {
+        let t = t.clamp(0.0, 1.0);
+        let (from, to) = (self.to_rgb(), other.to_rgb());
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * t).round() as u8;
+        Color::Rgb(mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+    }
+
+    // the color |factor| of the way to black
+    pub fn dim(self, factor: f32) -> Color {
+        self.blend(Color::Rgb(0, 0, 0), factor)
+    }
+
+    // the closest of the 16 named colors, for terminals not supporting more
+    pub fn to_nearest_named(self) -> Color {
+        if !matches!(self, Color::Indexed(_) | Color::Rgb(..)) {
+            return self;
+        }
+        let (r, g, b) = self.to_rgb();
+        let distance = |&(nr, ng, nb): &(u8, u8, u8)| {
+            let square = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+            square(r, nr) + square(g, ng) + square(b, nb)
+        };
+        let nearest = (0..ANSI_RGB.len())
+            .min_by_key(|&index| distance(&ANSI_RGB[index]))
+            .unwrap();
+        // the names are listed in palette order too
+        COLOR_NAMES[nearest].1
+    }
+}
+
+// the standard xterm values of the 16 named colors, in palette order
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+// parses color names such as "bright_red", "#rrggbb" hex or palette indices
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Color, String> {
+        let is_hex =
+            |digits: &str| digits.len() == 6 && digits.chars().all(|c| c.is_ascii_hexdigit());
+        let is_index =
+            |digits: &str| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit());
+        if let Some(&(_, color)) = COLOR_NAMES.iter().find(|&&(name, _)| name == s) {
+            Ok(color)
+        } else if s.starts_with('#') && is_hex(&s[1..]) {
+            let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).unwrap();
+            Ok(Color::Rgb(channel(1), channel(3), channel(5)))
+        } else if is_index(s) {
+            s.parse()
+                .map(Color::Indexed)
+                .map_err(|_| format!("Color index {} is out of range 0-255.", s))
+        } else {
+            Err(format!(
+                "Invalid color \"{}\", expected a name such as \"bright_red\", #rrggbb or an index 0-255.",
+                s
+            ))
+        }
+    }
+}
+
+// prints colors in the forms they're parsed from
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Color::Indexed(index) => write!(f, "{}", index),
+            Color::Rgb(r, g, b) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            named => {
+                let &(name, _) = COLOR_NAMES
+                    .iter()
+                    .find(|&&(_, color)| color == named)
+                    .unwrap();
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
+
+// colors serialize to the same strings they're parsed from
+#[cfg(feature = "serde")]
+impl serde::Serialize for Color {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Color {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+        let color = <String as serde::Deserialize>::deserialize(deserializer)?;
+        color.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/*
+ * The sizes a line can be drawn in, as set by the terminal's DEC line
+ * attributes. Lines of any size but normal take up two columns for each
+ * character, and a double height line is drawn as two of them, one showing
+ * the top halves of the characters and the other the bottom halves.
+ */
+#[allow(dead_code)] // not used by the editor yet
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum LineSize {
+    Normal,
+    DoubleWidth,
+    DoubleHeightTop,
+    DoubleHeightBottom,
+}
+
+/*
+ * The shapes the terminal can draw the cursor in.
+ */
+#[allow(dead_code)] // not used by the editor yet
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Bar,
+}
+
+/*
+ * Where the widths of characters are looked up, either the Unicode tables this
+ * is built with or the C library's wcwidth, which goes by the locale and is
+ * what many terminals themselves use.
+ */
+#[allow(dead_code)] // not used by the editor yet
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum WidthMode {
+    UnicodeWidth,
+    Wcwidth,
+}
+
+/*
+ * How text is lined up with the edges of the space it's put in.
+ */
+#[allow(dead_code)] // not used by the editor yet
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/*
+ * Attributes a cell can be drawn with on top of its colors.
+ */
+bitflags::bitflags! {
+    pub struct Attrs: u8 {
+        const BOLD      = 1 << 0;
+        const DIM       = 1 << 1;
+        const ITALIC    = 1 << 2;
+        const UNDERLINE = 1 << 3;
+        const REVERSE   = 1 << 4;
+    }
+}
+
+#[cfg(feature = "serde")]
+const ATTR_NAMES: [(&str, Attrs); 5] = [
+    ("bold", Attrs::BOLD),
+    ("dim", Attrs::DIM),
+    ("italic", Attrs::ITALIC),
+    ("underline", Attrs::UNDERLINE),
+    ("reverse", Attrs::REVERSE),
+];
+
+// attributes serialize to a list of their names, e.g. ["bold", "underline"]
+#[cfg(feature = "serde")]
+impl serde::Serialize for Attrs {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            ATTR_NAMES
+                .iter()
+                .filter(|&&(_, attr)| self.contains(attr))
+                .map(|&(name, _)| name),
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Attrs {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Attrs, D::Error> {
+        let names = <Vec<String> as serde::Deserialize>::deserialize(deserializer)?;
+        names.iter().try_fold(Attrs::empty(), |attrs, name| {
+            ATTR_NAMES
+                .iter()
+                .find(|&&(attr_name, _)| attr_name == name)
+                .map(|&(_, attr)| attrs | attr)
+                .ok_or_else(|| serde::de::Error::custom(format!("Invalid attribute \"{}\".", name)))
+        })
+    }
+}
+
+/*
+ * Style is the look of a cell, its colors along with any attributes.
+ */
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    #[cfg_attr(feature = "serde", serde(default = "Attrs::empty"))]
+    pub attrs: Attrs,
+}
+
+impl Style {
+    // plain colors without any attributes
+    pub fn new(fg: Color, bg: Color) -> Style {
+        Style {
+            fg,
+            bg,
+            attrs: Attrs::empty(),
+        }
+    }
+}
+
+/*
+ * The roles colors play on the screen, which a theme maps to actual colors.
+ */
+#[allow(dead_code)] // not all roles are used by the editor yet
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Foreground,
+    Background,
+    StatusBar,
+    LineNumber,
+    SelectionFg,
+    SelectionBg,
+    CursorLine,
+}
+
+/*
+ * Theme decides which colors to use for each role.
+ */
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Theme {
+    pub foreground: Color,
+    pub background: Color,
+    pub status_bar: Color,
+    pub line_number: Color,
+    pub selection_fg: Color,
+    pub selection_bg: Color,
+    pub cursor_line: Color,
+}
+
+#[allow(dead_code)] // the editor doesn't make use of all of these yet
+impl Theme {
+    pub fn dark() -> Theme {
+        Theme {
+            foreground: Color::White,
+            background: Color::Black,
+            status_bar: Color::Cyan,
+            line_number: Color::BrightBlack,
+            selection_fg: Color::Black,
+            selection_bg: Color::BrightWhite,
+            cursor_line: Color::BrightBlack,
+        }
+    }
+
+    pub fn light() -> Theme {
+        Theme {
+            foreground: Color::Black,
+            background: Color::BrightWhite,
+            status_bar: Color::Blue,
+            line_number: Color::BrightBlack,
+            selection_fg: Color::BrightWhite,
+            selection_bg: Color::Blue,
+            cursor_line: Color::White,
+        }
+    }
+
+    #[must_use]
+    pub fn color(&self, role: Role) -> Color {
+        match role {
+            Role::Foreground => self.foreground,
+            Role::Background => self.background,
+            Role::StatusBar => self.status_bar,
+            Role::LineNumber => self.line_number,
+            Role::SelectionFg => self.selection_fg,
+            Role::SelectionBg => self.selection_bg,
+            Role::CursorLine => self.cursor_line,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::dark()
+    }
+}
+
+// what the terminal sends on gaining and losing focus, once asked to
+pub const FOCUS_GAINED: &str = "\x1B[I";
+pub const FOCUS_LOST: &str = "\x1B[O";
+
+// whether the process was continued after having been stopped, with the screen
+// yet to be refreshed for it by Screen::refresh_if_continued
+#[allow(dead_code)] // for input readers, which the editor doesn't use yet
+#[must_use]
+pub fn is_continued() -> bool {
+    job_control::is_continued()
+}
+
+/*
+ * Helper module for job control, i.e. being stopped by ctrl-z and continued by
+ * the shell. The terminal is handed back right before stopping, by writing the
+ * sequence last set by the screen and putting back the terminal modes from
+ * before it was set up, without touching the screen state since that's not
+ * safe from a signal handler. Once continued the modes set up since, like raw
+ * mode, are put back and being continued is noted for the screen to be
+ * refreshed.
+ */
+#[cfg_attr(test, allow(dead_code))] // tests don't make use of all these
+mod job_control {
+    use std::mem;
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+    use std::sync::OnceLock;
+
+    const MAX_SUSPEND_SEQUENCE: usize = 256;
+
+    static CONTINUED: AtomicBool = AtomicBool::new(false);
+    // the terminal modes from before the screen was set up
+    static TERMIOS: OnceLock<libc::termios> = OnceLock::new();
+    // kept in atomics, as the handler may well run while it's being set
+    static SUSPEND_SEQUENCE: [AtomicU8; MAX_SUSPEND_SEQUENCE] =
+        [const { AtomicU8::new(0) }; MAX_SUSPEND_SEQUENCE];
+    static SUSPEND_SEQUENCE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+    // have |sequence| written when stopped; one too long to keep is dropped
+    // rather than cut short in the middle of an escape sequence
+    pub fn set_suspend_sequence(sequence: &str) {
+        SUSPEND_SEQUENCE_LEN.store(0, Ordering::SeqCst);
+        if sequence.len() <= MAX_SUSPEND_SEQUENCE {
+            for (slot, &byte) in SUSPEND_SEQUENCE.iter().zip(sequence.as_bytes()) {
+                slot.store(byte, Ordering::Relaxed);
+            }
+            SUSPEND_SEQUENCE_LEN.store(sequence.len(), Ordering::SeqCst);
+        }
+    }
+
+    extern "C" fn on_sigtstp(_: libc::c_int) {
+        let mut sequence = [0u8; MAX_SUSPEND_SEQUENCE];
+        let len = SUSPEND_SEQUENCE_LEN.load(Ordering::SeqCst);
+        for (byte, slot) in sequence.iter_mut().zip(&SUSPEND_SEQUENCE[..len]) {
+            *byte = slot.load(Ordering::Relaxed);
+        }
+        unsafe {
+            let mut modes: libc::termios = mem::zeroed();
+            let got_modes = libc::tcgetattr(libc::STDIN_FILENO, &mut modes) == 0;
+            libc::write(
+                libc::STDOUT_FILENO,
+                sequence.as_ptr() as *const libc::c_void,
+                len,
+            );
+            if let Some(termios) = TERMIOS.get() {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, termios);
+            }
+            // stop for real, the handler being put back once continued; the
+            // signal is blocked while handled, so it's unblocked to stop
+            // right here rather than once the handler returns
+            libc::signal(libc::SIGTSTP, libc::SIG_DFL);
+            let mut signals: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGTSTP);
+            libc::sigprocmask(libc::SIG_UNBLOCK, &signals, ptr::null_mut());
+            libc::raise(libc::SIGTSTP);
+            // continued
+            if got_modes {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &modes);
+            }
+        }
+    }
+
+    extern "C" fn on_sigcont(_: libc::c_int) {
+        CONTINUED.store(true, Ordering::SeqCst);
+        watch_sigtstp();
+    }
+
+    fn watch_sigtstp() {
+        let handler = on_sigtstp as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGTSTP, handler as libc::sighandler_t);
+        }
+    }
+
+    // the terminal modes are saved here, so this is to be called before
+    // they're set up, e.g. before input switches to raw mode
+    pub fn watch() {
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0 {
+                TERMIOS.set(termios).ok();
+            }
+        }
+        let handler = on_sigcont as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGCONT, handler as libc::sighandler_t);
+        }
+        watch_sigtstp();
+    }
+
+    // whether the process was continued since last asked
+    pub fn take_continued() -> bool {
+        CONTINUED.swap(false, Ordering::SeqCst)
+    }
+
+    // whether the process was continued, without taking note of it
+    pub fn is_continued() -> bool {
+        CONTINUED.load(Ordering::SeqCst)
+    }
+}
+
+/*
+ * Helper module to capture the ugly. Provides a mean to poll the screen size.
+ */
+#[cfg(not(test))]
+mod term_size {
+    const STDOUT_FILENO: libc::c_int = 1;
+
+    #[cfg(target_os = "macos")]
+    const TIOCGWINSZ: libc::c_ulong = 0x40087468;
+    #[cfg(target_os = "linux")]
+    const TIOCGWINSZ: libc::c_ulong = 0x5413;
+
+    pub fn size() -> Option<(u16, u16)> {
+        #[allow(dead_code)] // not interested in pixel sizes
+        struct WinSize {
+            rows: libc::c_ushort,     // rows, in screen cells
+            cols: libc::c_ushort,     // columns, in screen cells
+            h_pixels: libc::c_ushort, // horizontal size, pixels
+            v_pixels: libc::c_ushort, // vertical size, pixels
+        }
+
+        unsafe {
+            let mut size = WinSize {
+                rows: 0,
+                cols: 0,
+                h_pixels: 0,
+                v_pixels: 0,
+            };
+            match libc::ioctl(STDOUT_FILENO, TIOCGWINSZ, &mut size) {
+                0 => Some((size.rows, size.cols)),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    // a writer handed to a screen whose output can be inspected by the test
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Output {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.borrow_mut().split_off(0)).unwrap()
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn screen_with_output(size: Size) -> (Screen, Output) {
+        let output = Output::default();
+        let mut screen = Screen::with_writer(Box::new(output.clone()), size);
+        screen.flush();
+        output.take();
+        (screen, output)
+    }
+
+    #[test]
+    fn draw_box() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(5, 5));
+        screen.draw_box(Rect(Cell(1, 1), Size(3, 3)), Color::White, Color::Black);
+        let at = |row: usize, col: usize| screen.buffer.cells.get(row * 5 + col).map(|(c, _)| c);
+        assert_eq!(at(1, 1), Some('┌'));
+        assert_eq!(at(1, 2), Some('─'));
+        assert_eq!(at(1, 3), Some('┐'));
+        assert_eq!(at(2, 1), Some('│'));
+        assert_eq!(at(2, 2), None);
+        assert_eq!(at(2, 3), Some('│'));
+        assert_eq!(at(3, 1), Some('└'));
+        assert_eq!(at(3, 2), Some('─'));
+        assert_eq!(at(3, 3), Some('┘'));
+        assert_eq!(at(0, 0), None);
+        assert_eq!(at(4, 4), None);
+    }
+
+    #[test]
+    fn ascii_only() {
+        let (mut screen, output) = screen_with_output(Size(3, 8));
+        screen.set_ascii_only(true);
+        screen.draw_box(Rect(Cell(0, 0), Size(3, 3)), Color::White, Color::Black);
+        screen.flush();
+        let written = output.take();
+        assert!(written.contains("+-+"));
+        assert!(written.is_ascii());
+        let style = Style::new(Color::White, Color::Black);
+        screen.put_listchars(Cell(1, 3), "\tx ", style, style);
+        screen.status_line(
+            2,
+            "status line",
+            "",
+            "",
+            Style::new(Color::White, Color::Black),
+        );
+        screen.flush();
+        assert!(output.take().is_ascii());
+        assert_eq!(row_text(&screen, 1), "||>----");
+        assert_eq!(row_text(&screen, 2), "statu...");
+    }
+
+    #[test]
+    fn coalesce_blank_row() {
+        let (mut screen, output) = screen_with_output(Size(2, 10));
+        screen.draw_hline(Cell(1, 0), 10, ' ', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[2;1H\x1B[37m\x1B[40m          ");
+
+        // with back color erase the run to the end of the row is erased instead
+        screen.terminal.bce = true;
+        screen.draw_hline(Cell(1, 0), 10, ' ', Color::White, Color::Blue);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[2;1H\x1B[44m\x1B[0K");
+    }
+
+    #[test]
+    fn fill_rect() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(4, 4));
+        screen.fill_rect(
+            Rect(Cell(1, 1), Size(2, 2)),
+            '#',
+            Color::White,
+            Color::Black,
+        );
+        let filled: Vec<usize> = (0..16)
+            .filter(|&i| screen.buffer.cells.is_occupied(i))
+            .collect();
+        assert_eq!(filled, vec![5, 6, 9, 10]);
+
+        // wide characters are put every other column
+        screen.fill_rect(
+            Rect(Cell(0, 0), Size(1, 3)),
+            'あ',
+            Color::White,
+            Color::Black,
+        );
+        assert_eq!(screen.buffer.get(Cell(0, 0)).map(|(c, _)| c), Some('あ'));
+        assert_eq!(screen.buffer.get(Cell(0, 1)), None);
+        assert_eq!(screen.buffer.get(Cell(0, 2)), None);
+    }
+
+    #[test]
+    fn colors_sent_once() {
+        let (mut screen, output) = screen_with_output(Size(1, 10));
+        for col in 0..10 {
+            screen.put(Cell(0, col), 'x', Color::Red, Color::Black);
+        }
+        screen.flush();
+        let output = output.take();
+        assert_eq!(output.matches("\x1B[31m").count(), 1);
+        assert_eq!(output.matches("\x1B[40m").count(), 1);
+    }
+
+    #[test]
+    fn erase_lost_tail() {
+        let (mut screen, output) = screen_with_output(Size(1, 10));
+        for (col, character) in "0123456789".chars().enumerate() {
+            screen.put(Cell(0, col as u16), character, Color::White, Color::Black);
+        }
+        screen.flush();
+        output.take();
+        screen.clear_rect(Rect(Cell(0, 4), Size(1, 6)));
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1;5H\x1B[39;49m\x1B[0K");
+        assert_eq!(screen.buffer.get(Cell(0, 3)).map(|(c, _)| c), Some('3'));
+        assert_eq!(screen.buffer.get(Cell(0, 4)), None);
+
+        // blanks short of the edge are written out
+        screen.put(Cell(0, 4), '4', Color::White, Color::Black);
+        screen.flush();
+        output.take();
+        screen.clear_rect(Rect(Cell(0, 2), Size(1, 2)));
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[3D\x1B[39;49m  ");
+    }
+
+    // the characters on |row|, leaving out the continuations of wide ones
+    fn row_text(screen: &Screen, row: u16) -> String {
+        let Size(_, cols) = screen.size();
+        (0..cols)
+            .filter_map(|col| screen.buffer.get(Cell(row, col)).map(|(c, _)| c))
+            .collect()
+    }
+
+    #[test]
+    fn attributes_sent() {
+        let (mut screen, output) = screen_with_output(Size(1, 3));
+        let mut style = Style::new(Color::White, Color::Black);
+        style.attrs = Attrs::BOLD | Attrs::UNDERLINE;
+        screen.put_styled(Cell(0, 0), 'a', style);
+        screen.put_styled(Cell(0, 1), 'b', style);
+        screen.put(Cell(0, 2), 'c', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[1;1H\x1B[0;1;4m\x1B[37m\x1B[40mab\x1B[0m\x1B[37m\x1B[40mc"
+        );
+    }
+
+    #[test]
+    fn put_spans() {
+        let base = Style::new(Color::White, Color::Black);
+        let keyword = Style::new(Color::Blue, Color::Black);
+        let string = Style::new(Color::Green, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 8));
+        screen.put_spans(
+            Cell(0, 0),
+            "ab\"あc\"de",
+            base,
+            &[(0, 4, keyword), (2, 6, string)],
+        );
+        assert_eq!(row_text(&screen, 0), "ab\"あc\"d");
+        let style_at = |col| screen.buffer.get(Cell(0, col)).map(|(_, style)| style);
+        assert_eq!(style_at(1), Some(keyword));
+        assert_eq!(style_at(2), Some(string));
+        assert_eq!(style_at(3), Some(string));
+        assert_eq!(style_at(4), None);
+        assert_eq!(style_at(6), Some(string));
+        assert_eq!(style_at(7), Some(base));
+    }
+
+    #[test]
+    fn put_listchars() {
+        let style = Style::new(Color::White, Color::Black);
+        let trail_style = Style::new(Color::Blue, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(2, 16));
+        screen.put_listchars(Cell(0, 0), "ab\tc   ", style, trail_style);
+        assert_eq!(row_text(&screen, 0), "ab▸─────c···");
+        let style_at =
+            |screen: &Screen, col| screen.buffer.get(Cell(0, col)).map(|(_, style)| style);
+        assert_eq!(style_at(&screen, 1), Some(style));
+        assert_eq!(style_at(&screen, 2), Some(trail_style));
+        assert_eq!(style_at(&screen, 8), Some(style));
+        assert_eq!(style_at(&screen, 9), Some(trail_style));
+        screen.set_tab_width(4);
+        screen.put_listchars(Cell(1, 0), "\tx\t ", style, trail_style);
+        assert_eq!(row_text(&screen, 1), "▸───x▸──·");
+    }
+
+    #[test]
+    fn put_wrapped() {
+        let style = Style::new(Color::White, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(4, 8));
+        let rect = Rect(Cell(0, 1), Size(4, 5));
+        assert_eq!(screen.put_wrapped(rect, "abcdあfghijk", style, '>'), 3);
+        assert_eq!(row_text(&screen, 0), "abcd");
+        assert_eq!(row_text(&screen, 1), ">あfg");
+        assert_eq!(row_text(&screen, 2), ">hijk");
+        assert_eq!(row_text(&screen, 3), "");
+        let rect = Rect(Cell(0, 1), Size(2, 5));
+        assert_eq!(screen.put_wrapped(rect, "0123456789abcdef", style, '>'), 2);
+        assert_eq!(row_text(&screen, 1), ">5678");
+        assert_eq!(row_text(&screen, 2), ">hijk");
+        assert_eq!(screen.put_wrapped(rect, "", style, '>'), 1);
+    }
+
+    #[test]
+    fn put_run() {
+        let (mut screen, output) = screen_with_output(Size(2, 20));
+        let width = screen.put_run(
+            Cell(0, 2),
+            "function",
+            Color::Blue,
+            Color::Black,
+            Attrs::BOLD,
+        );
+        assert_eq!(width, 8);
+        screen.flush();
+        let run = output.take();
+        assert_eq!(run, "\x1B[1;3H\x1B[0;1m\x1B[34m\x1B[40mfunction");
+        // puts of one character at a time are coalesced into the same run
+        let (mut screen, output) = screen_with_output(Size(2, 20));
+        let style = Style {
+            fg: Color::Blue,
+            bg: Color::Black,
+            attrs: Attrs::BOLD,
+        };
+        for (col, character) in (2..).zip("function".chars()) {
+            screen.put_styled(Cell(0, col), character, style);
+        }
+        screen.flush();
+        assert_eq!(output.take().len(), run.len());
+    }
+
+    #[test]
+    fn get() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(2, 4));
+        screen.put(Cell(0, 0), 'a', Color::Red, Color::Black);
+        screen.put(Cell(0, 1), 'あ', Color::Green, Color::Blue);
+        assert_eq!(
+            screen.get(Cell(0, 0)),
+            Some(('a', Color::Red, Color::Black))
+        );
+        assert_eq!(
+            screen.get(Cell(0, 1)),
+            Some(('あ', Color::Green, Color::Blue))
+        );
+        assert_eq!(
+            screen.get(Cell(0, 2)),
+            Some(('あ', Color::Green, Color::Blue))
+        );
+        assert_eq!(screen.get(Cell(0, 3)), None);
+        assert_eq!(screen.get(Cell(1, 0)), None);
+        assert_eq!(screen.get(Cell(2, 0)), None);
+    }
+
+    #[test]
+    fn save_and_restore_region() {
+        let (mut screen, output) = screen_with_output(Size(3, 6));
+        let style = Style::new(Color::Green, Color::Black);
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        screen.put_str(Cell(0, 0), "abcdef", style);
+        screen.put_str(Cell(1, 0), &format!("gあ{}h", family), style);
+        screen
+            .put_width(Cell(2, 1), '★', 2, Color::Red, Color::Blue)
+            .unwrap();
+        screen.flush();
+        output.take();
+        let rect = Rect(Cell(0, 1), Size(3, 4));
+        let before = CellIterator::new(rect)
+            .map(|cell| screen.buffer.contents(cell))
+            .collect::<Vec<_>>();
+        let saved = screen.save_rect(rect);
+        screen.fill_rect(rect, 'x', Color::White, Color::Black);
+        screen.flush();
+        output.take();
+        screen.restore_region(&saved);
+        let after = CellIterator::new(rect)
+            .map(|cell| screen.buffer.contents(cell))
+            .collect::<Vec<_>>();
+        assert_eq!(after, before);
+        assert_eq!(screen.dump(), format!("abcdef\ngあ{}h\n ★   ", family));
+        screen.flush();
+        assert!(output.take().contains(&format!("あ{}", family)));
+    }
+
+    #[test]
+    fn put_cells() {
+        let (mut screen, output) = screen_with_output(Size(2, 80));
+        let cells = [
+            ('a', Color::White, Color::Black),
+            ('b', Color::White, Color::Black),
+            ('c', Color::Red, Color::Black),
+            ('d', Color::White, Color::Black),
+            ('e', Color::White, Color::Black),
+        ];
+        screen.put_cells(Cell(0, 78), &cells);
+        screen.put_cells(
+            Cell(1, 77),
+            &[
+                ('x', Color::White, Color::Black),
+                ('あ', Color::White, Color::Black),
+                ('y', Color::White, Color::Black),
+            ],
+        );
+        screen.flush();
+        assert_eq!(row_text(&screen, 0), "ab");
+        assert_eq!(row_text(&screen, 1), "xあ");
+        assert_eq!(output.take(), "\x1B[1;79H\x1B[37m\x1B[40mab\x1B[2;78Hxあ");
+
+        // only the visible half of a double width row is put on
+        screen.set_line_size(0, LineSize::DoubleWidth);
+        screen.put_cells(Cell(0, 38), &cells);
+        assert_eq!(
+            screen.buffer.get(Cell(0, 39)),
+            Some(('b', Style::new(Color::White, Color::Black)))
+        );
+        assert_eq!(screen.buffer.get(Cell(0, 40)), None);
+        screen.put_cells(Cell(0, u16::MAX), &cells);
+
+        // characters that can't be shown are replaced, as by put
+        screen.set_width_mode(WidthMode::Wcwidth);
+        screen.put_cells(Cell(1, 0), &[('é', Color::White, Color::Black)]);
+        screen.put(Cell(1, 1), 'é', Color::White, Color::Black);
+        assert_eq!(screen.buffer.get(Cell(1, 0)), screen.buffer.get(Cell(1, 1)));
+        assert_eq!(
+            screen.buffer.get(Cell(1, 0)).map(|(c, _)| c),
+            Some('\u{FFFD}')
+        );
+    }
+
+    #[test]
+    fn grapheme_clusters() {
+        let style = Style::new(Color::White, Color::Black);
+        let (mut screen, output) = screen_with_output(Size(2, 6));
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(
+            screen.put_str(Cell(0, 0), &format!("{}x", family), style),
+            3
+        );
+        assert_eq!(screen.buffer.get(Cell(0, 0)), Some(('\u{1F468}', style)));
+        assert_eq!(screen.buffer.get(Cell(0, 1)), None);
+        assert_eq!(screen.buffer.get(Cell(0, 2)), Some(('x', style)));
+        assert_eq!(screen.dump(), format!("{}x   \n      ", family));
+        screen.flush();
+        assert!(output.take().contains(&format!("{}x", family)));
+
+        // putting the same cluster again changes nothing, a lone character does
+        screen.put_str(Cell(0, 0), family, style);
+        screen.flush();
+        assert_eq!(output.take(), "");
+        screen.put_str(Cell(0, 0), "\u{1F468}", style);
+        assert_eq!(screen.dump(), "\u{1F468}x   \n      ");
+    }
+
+    #[test]
+    fn put_width() {
+        let (fg, bg) = (Color::White, Color::Black);
+        let (mut screen, output) = screen_with_output(Size(1, 4));
+        screen.put_str(Cell(0, 0), "abc", Style::new(fg, bg));
+        assert_eq!(screen.put_width(Cell(0, 0), '\u{E0A0}', 2, fg, bg), Ok(()));
+        assert_eq!(
+            screen.buffer.get(Cell(0, 0)),
+            Some(('\u{E0A0}', Style::new(fg, bg)))
+        );
+        assert_eq!(screen.buffer.get(Cell(0, 1)), None);
+        assert_eq!(screen.buffer.span(Cell(0, 0)), 2);
+        assert_eq!(row_text(&screen, 0), "\u{E0A0}c");
+        screen.flush();
+        // the cursor is placed anew after the glyph of unknown width
+        assert_eq!(output.take(), "\x1B[1;1H\x1B[37m\x1B[40m\u{E0A0}\x1B[1;3Hc");
+
+        // putting it again the usual way takes its width from the character
+        screen.put(Cell(0, 0), '\u{E0A0}', fg, bg);
+        assert_eq!(screen.buffer.span(Cell(0, 0)), 1);
+        assert!(screen.put_width(Cell(0, 0), 'x', 3, fg, bg).is_err());
+        assert!(screen.put_width(Cell(0, 0), 'x', 0, fg, bg).is_err());
+    }
+
+    #[test]
+    fn ambiguous_wide() {
+        let style = Style::new(Color::White, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 4));
+        assert_eq!(screen.put_str(Cell(0, 0), "\u{A1}a", style), 2);
+        assert_eq!(screen.buffer.span(Cell(0, 0)), 1);
+        assert_eq!(screen.buffer.get(Cell(0, 1)), Some(('a', style)));
+
+        screen.set_ambiguous_wide(true);
+        assert_eq!(screen.buffer.get(Cell(0, 1)), None);
+        assert_eq!(screen.put_str(Cell(0, 0), "\u{A1}a", style), 3);
+        assert_eq!(screen.buffer.span(Cell(0, 0)), 2);
+        assert_eq!(screen.buffer.get(Cell(0, 1)), None);
+        assert_eq!(screen.buffer.get(Cell(0, 2)), Some(('a', style)));
+    }
+
+    #[test]
+    fn truncate_to_width() {
+        let text = "日本語のファイル名.txt";
+        assert_eq!(super::truncate_to_width(text, 7), "日本語…");
+        assert_eq!(super::truncate_to_width(text, 8), "日本語…");
+        assert_eq!(super::truncate_to_width(text, 1), "…");
+        assert_eq!(super::truncate_to_width(text, 0), "");
+        assert_eq!(super::truncate_to_width(text, 22), text);
+        assert!(matches!(
+            super::truncate_to_width(text, 22),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(truncate(text, 6, "..."), "日...");
+        assert_eq!(truncate(text, 2, "..."), "..");
+    }
+
+    #[test]
+    fn wrap_to_width() {
+        let text = "The quick brown fox jumps over the lazy dog, and then it naps.";
+        assert_eq!(
+            super::wrap_to_width(text, 20),
+            [
+                "The quick brown fox",
+                "jumps over the lazy",
+                "dog, and then it",
+                "naps."
+            ]
+        );
+        assert_eq!(
+            super::wrap_to_width("see /a/very/long/path/to/a/file now", 10),
+            ["see", "/a/very/lo", "ng/path/to", "/a/file", "now"]
+        );
+        assert_eq!(
+            super::wrap_to_width("日本語のテキスト", 5),
+            ["日本", "語の", "テキ", "スト"]
+        );
+        assert_eq!(super::wrap_to_width("a\n\nb  c", 5), ["a", "", "b c"]);
+        assert!(super::wrap_to_width("a", 0).is_empty());
+    }
+
+    #[test]
+    fn width_mode() {
+        // the locale is never set up by the tests, so the C library only knows
+        // the widths of ASCII characters
+        let style = Style::new(Color::White, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 4));
+        assert_eq!(screen.buffer.char_width('é'), Some(1));
+        screen.put_str(Cell(0, 0), "éa", style);
+        assert_eq!(screen.buffer.get(Cell(0, 1)), Some(('a', style)));
+
+        screen.set_width_mode(WidthMode::Wcwidth);
+        assert_eq!(screen.buffer.get(Cell(0, 1)), None);
+        assert_eq!(char_width('é', false, WidthMode::Wcwidth), None);
+        assert_eq!(char_width('a', false, WidthMode::Wcwidth), Some(1));
+        assert_eq!(char_width('é', false, WidthMode::UnicodeWidth), Some(1));
+    }
+
+    #[test]
+    fn replacement_char() {
+        // in the C locale the tests run in, the C library doesn't consider 'é'
+        // printable
+        let style = Style::new(Color::White, Color::Black);
+        let (mut screen, output) = screen_with_output(Size(1, 4));
+        screen.set_width_mode(WidthMode::Wcwidth);
+        screen.flush();
+        output.take();
+        screen.put_styled(Cell(0, 0), 'é', style);
+        assert_eq!(screen.buffer.get(Cell(0, 0)), Some(('\u{FFFD}', style)));
+        assert_eq!(screen.put_str(Cell(0, 1), "éa", style), 2);
+        assert_eq!(screen.buffer.get(Cell(0, 2)), Some(('a', style)));
+        screen.flush();
+        // nor is the replacement itself, so the cursor is placed after each
+        assert_eq!(
+            output.take(),
+            "\x1B[1;1H\x1B[37m\x1B[40m\u{FFFD}\x1B[1;2H\u{FFFD}\x1B[1;3Ha"
+        );
+
+        screen.set_replacement_char('?');
+        screen.put_styled(Cell(0, 0), 'é', style);
+        assert_eq!(screen.buffer.get(Cell(0, 0)), Some(('?', style)));
+        screen.put_styled(Cell(0, 3), '\x07', style);
+        assert_eq!(screen.buffer.get(Cell(0, 3)), Some(('\x07', style)));
+    }
+
+    #[test]
+    fn draw_cursor_on_wide_character() {
+        let style = Style::new(Color::White, Color::Black);
+        let mut reversed = style;
+        reversed.attrs = Attrs::REVERSE;
+        let (mut screen, output) = screen_with_output(Size(1, 5));
+        screen.put_str(Cell(0, 0), "aあb", style);
+        screen.flush();
+        output.take();
+        for &col in &[1, 2] {
+            let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 5));
+            screen.put_str(Cell(0, 0), "aあb", style);
+            let covered = screen.draw_cursor(Cell(0, col));
+            assert_eq!(covered, Some(Rect(Cell(0, 1), Size(1, 2))));
+            assert_eq!(screen.buffer.get(Cell(0, 1)), Some(('あ', reversed)));
+            assert_eq!(screen.buffer.get(Cell(0, 2)), None);
+            assert_eq!(screen.buffer.get(Cell(0, 3)), Some(('b', style)));
+        }
+        screen.draw_cursor(Cell(0, 2));
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[3D\x1B[0;7m\x1B[37m\x1B[40mあ");
+        assert_eq!(screen.draw_cursor(Cell(0, 5)), None);
+    }
+
+    #[test]
+    fn highlight_row() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(2, 5));
+        let mut bold = Style::new(Color::Red, Color::Black);
+        bold.attrs = Attrs::BOLD;
+        screen.put_styled(Cell(1, 0), 'a', bold);
+        screen.put(Cell(1, 1), 'あ', Color::Green, Color::Black);
+        screen.put(Cell(0, 0), 'x', Color::Green, Color::Black);
+        for &bg in &[Color::Blue, Color::Indexed(236)] {
+            screen.highlight_row(1, bg);
+            assert_eq!(row_text(&screen, 1), "aあ  ");
+            assert_eq!(
+                screen.buffer.get(Cell(1, 0)),
+                Some(('a', Style { bg, ..bold }))
+            );
+            assert_eq!(
+                screen.buffer.get(Cell(1, 1)),
+                Some(('あ', Style::new(Color::Green, bg)))
+            );
+            assert_eq!(screen.buffer.get(Cell(1, 2)), None);
+            assert_eq!(
+                screen.buffer.get(Cell(1, 4)),
+                Some((' ', Style::new(Color::White, bg)))
+            );
+        }
+        assert_eq!(
+            screen.buffer.get(Cell(0, 0)),
+            Some(('x', Style::new(Color::Green, Color::Black)))
+        );
+        screen.highlight_row(2, Color::Blue);
+    }
+
+    #[test]
+    fn set_bg_range() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 8));
+        screen.put_str(Cell(0, 0), "abあcd", Style::new(Color::Red, Color::Black));
+        screen.set_bg_range(Cell(0, 1), 2, Color::Blue);
+        screen.set_bg_range(Cell(0, 6), 4, Color::Blue);
+        assert_eq!(row_text(&screen, 0), "abあcd  ");
+        let bg_at =
+            |screen: &Screen, col| screen.buffer.get(Cell(0, col)).map(|(_, style)| style.bg);
+        let bgs: Vec<_> = (0..8).map(|col| bg_at(&screen, col)).collect();
+        let (black, blue) = (Some(Color::Black), Some(Color::Blue));
+        assert_eq!(bgs, vec![black, blue, blue, None, black, black, blue, blue]);
+        assert_eq!(
+            screen.buffer.get(Cell(0, 1)),
+            Some(('b', Style::new(Color::Red, Color::Blue)))
+        );
+        assert_eq!(
+            screen.buffer.get(Cell(0, 7)),
+            Some((' ', Style::new(Color::White, Color::Blue)))
+        );
+        // starting on the second column of a wide character covers all of it
+        screen.set_bg_range(Cell(0, 3), 1, Color::Green);
+        assert_eq!(bg_at(&screen, 2), Some(Color::Green));
+        assert_eq!(bg_at(&screen, 4), black);
+    }
+
+    #[test]
+    fn colorcolumn() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(3, 6));
+        let style = Style::new(Color::Red, Color::Black);
+        screen.put_str(Cell(0, 0), "abcdef", style);
+        screen.put_str(Cell(1, 0), "ab", style);
+        screen.colorcolumn(3, Rect(Cell(0, 1), Size(2, 4)), Color::Blue);
+        screen.colorcolumn(0, Rect(Cell(0, 1), Size(2, 4)), Color::Blue);
+        assert_eq!(row_text(&screen, 0), "abcdef");
+        let bgs = |row| {
+            (0..6)
+                .map(|col| screen.buffer.get(Cell(row, col)).map(|(_, style)| style.bg))
+                .collect::<Vec<_>>()
+        };
+        let (black, blue) = (Some(Color::Black), Some(Color::Blue));
+        assert_eq!(bgs(0), vec![black, black, black, blue, black, black]);
+        assert_eq!(bgs(1), vec![black, black, None, blue, None, None]);
+        assert_eq!(bgs(2), vec![None; 6]);
+        assert_eq!(
+            screen.buffer.get(Cell(0, 3)),
+            Some((
+                'd',
+                Style {
+                    bg: Color::Blue,
+                    ..style
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn dim_except() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(3, 4));
+        let style = Style::new(Color::Rgb(200, 100, 0), Color::Rgb(0, 0, 100));
+        for row in 0..3 {
+            screen.put_str(Cell(row, 0), "abc", style);
+        }
+        screen.dim_except(Rect(Cell(1, 1), Size(1, 2)), 0.5);
+        let dimmed = Style::new(Color::Rgb(100, 50, 0), Color::Rgb(0, 0, 50));
+        assert_eq!(screen.buffer.get(Cell(1, 1)), Some(('b', style)));
+        assert_eq!(screen.buffer.get(Cell(1, 2)), Some(('c', style)));
+        assert_eq!(screen.buffer.get(Cell(1, 0)), Some(('a', dimmed)));
+        assert_eq!(screen.buffer.get(Cell(0, 1)), Some(('b', dimmed)));
+        assert_eq!(
+            screen.buffer.get(Cell(2, 3)),
+            Some((
+                ' ',
+                Style::new(Color::White.dim(0.5), Color::Black.dim(0.5))
+            ))
+        );
+    }
+
+    #[test]
+    fn highlight_matches() {
+        let base = Style::new(Color::White, Color::Black);
+        let mut found = base;
+        found.attrs = Attrs::REVERSE;
+        let (mut screen, output) = screen_with_output(Size(2, 10));
+        screen.put_str(Cell(0, 0), "abcdefghij", base);
+        screen.flush();
+        output.take();
+        let ranges = [(Cell(0, 2), 3), (Cell(0, 4), 3), (Cell(1, 6), 5)];
+        screen.highlight_matches(Rect(Cell(0, 0), Size(2, 8)), &ranges, found);
+        assert_eq!(row_text(&screen, 0), "abcdefghij");
+        let styles: Vec<_> = (0..10)
+            .map(|col| screen.buffer.get(Cell(0, col)).map(|(_, style)| style))
+            .collect();
+        assert_eq!(
+            styles[1..8],
+            [
+                Some(base),
+                Some(found),
+                Some(found),
+                Some(found),
+                Some(found),
+                Some(found),
+                Some(base)
+            ]
+        );
+        assert_eq!(screen.buffer.get(Cell(1, 7)), Some((' ', found)));
+        assert_eq!(screen.buffer.get(Cell(1, 8)), None);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[1;3H\x1B[0;7m\x1B[37m\x1B[40mcdefg\x1B[2;7H  "
+        );
+    }
+
+    #[test]
+    fn status_line() {
+        let style = Style::new(Color::Black, Color::Cyan);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(3, 20));
+        screen.status_line(0, "ab", "中文", "xyz", style);
+        assert_eq!(row_text(&screen, 0), "ab      中文     xyz");
+        assert_eq!(screen.buffer.get(Cell(0, 8)), Some(('中', style)));
+        assert_eq!(screen.buffer.get(Cell(0, 19)), Some(('z', style)));
+
+        screen.status_line(1, "aaaaaa", "centered text", "bbbbbb", style);
+        assert_eq!(row_text(&screen, 1), "aaaaaacentere…bbbbbb");
+
+        screen.status_line(2, "left side text", "middle", "right side", style);
+        assert_eq!(row_text(&screen, 2), "left side textright…");
+        screen.status_line(2, "a much too long left side", "", "", style);
+        assert_eq!(row_text(&screen, 2), "a much too long lef…");
+    }
+
+    #[test]
+    fn clear_resets_attributes() {
+        let (mut screen, output) = screen_with_output(Size(1, 2));
+        let mut style = Style::new(Color::White, Color::Black);
+        style.attrs = Attrs::BOLD;
+        screen.put_styled(Cell(0, 0), 'x', style);
+        screen.flush();
+        output.take();
+        screen.clear();
+        screen.put_styled(Cell(0, 0), 'x', style);
+        screen.flush();
+        // the attributes go out again after the reset rather than being skipped
+        assert_eq!(
+            output.take(),
+            "\x1B[0m\x1B[2J\x1B[1;1H\x1B[0;1m\x1B[37m\x1B[40mx"
+        );
+    }
+
+    #[test]
+    fn clear_style() {
+        let (mut screen, output) = screen_with_output(Size(2, 3));
+        let style = Style::new(Color::White, Color::Blue);
+        screen.put(Cell(0, 0), 'x', Color::Red, Color::Black);
+        screen.set_line_size(1, LineSize::DoubleWidth);
+        screen.flush();
+        output.take();
+        screen.set_clear_style(' ', Color::White, Color::Blue);
+        screen.clear();
+        for cell in CellIterator::new(Rect(Cell(0, 0), Size(2, 3))) {
+            assert_eq!(screen.buffer.get(cell), Some((' ', style)));
+        }
+        screen.flush();
+        let written = output.take();
+        assert!(!written.contains("\x1B[2J"));
+        assert!(written.starts_with("\x1B[1B\r\x1B#5"));
+        assert!(written.contains("\x1B[44m"));
+
+        screen.set_clear_style('~', Color::Blue, Color::Black);
+        screen.clear();
+        assert_eq!(row_text(&screen, 0), "~~~");
+        assert_eq!(row_text(&screen, 1), "~~~");
+        let style = Style::new(Color::Blue, Color::Black);
+        assert_eq!(screen.buffer.get(Cell(1, 2)), Some(('~', style)));
+        screen.flush();
+        assert!(output.take().contains("\x1B[34m\x1B[40m~~~"));
+    }
+
+    #[test]
+    fn cursor_blink() {
+        let (mut screen, output) = screen_with_output(Size(1, 1));
+        screen.set_cursor_shape(CursorShape::Bar);
+        screen.set_cursor_blink(false);
+        screen.set_cursor_blink(true);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[5 q\x1B[6 q\x1B[5 q");
+        screen.set_cursor_blink(false);
+        screen.set_cursor_shape(CursorShape::Underline);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[6 q\x1B[4 q");
+        drop(screen);
+        assert!(output.take().contains("\x1B[0 q"));
+    }
+
+    #[test]
+    fn soft_clear() {
+        let (mut screen, output) = screen_with_output(Size(10, 20));
+        screen.put(Cell(0, 0), 'a', Color::White, Color::Black);
+        screen.put(Cell(4, 7), 'b', Color::White, Color::Black);
+        screen.put(Cell(9, 3), 'c', Color::White, Color::Black);
+        screen.flush();
+        output.take();
+        screen.soft_clear();
+        screen.flush();
+        let written = output.take();
+        assert!(!written.contains("\x1B[2J"));
+        assert_eq!(written.matches(' ').count(), 3);
+        assert!((0..10).all(|row| row_text(&screen, row).is_empty()));
+        let stats = screen.last_frame_stats();
+        assert_eq!(stats.cells_written, 3);
+    }
+
+    #[test]
+    fn cursor_visible() {
+        let (mut screen, output) = screen_with_output(Size(1, 1));
+        screen.set_cursor_visible(false);
+        screen.flush();
+        // hidden from the start
+        assert_eq!(output.take(), "");
+        screen.set_cursor_visible(true);
+        screen.set_cursor_visible(true);
+        screen.set_cursor_visible(false);
+        screen.set_cursor_visible(false);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[?25h\x1B[?25l");
+        drop(screen);
+        assert!(output.take().contains("\x1B[?25h"));
+    }
+
+    #[test]
+    fn display_width() {
+        let lookups = || WIDTH_LOOKUPS.with(|lookups| lookups.get());
+        let before = lookups();
+        assert_eq!(super::display_width('a'), Some(1));
+        assert_eq!(super::display_width('~'), Some(1));
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 20));
+        screen.put_str(
+            Cell(0, 0),
+            "plain ascii text",
+            Style::new(Color::White, Color::Black),
+        );
+        assert_eq!(lookups(), before);
+        assert_eq!(super::display_width('中'), Some(2));
+        assert_eq!(super::display_width('\t'), None);
+        assert_eq!(lookups(), before + 2);
+    }
+
+    #[test]
+    fn style_stack() {
+        let outer = Style::new(Color::White, Color::Blue);
+        let inner = Style::new(Color::Yellow, Color::Black);
+        let base = Style::new(Color::Red, Color::Green);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 5));
+        screen.set_theme(Some(Theme {
+            foreground: Color::Red,
+            background: Color::Green,
+            ..Theme::default()
+        }));
+        screen.push_style(outer);
+        screen.put_char(Cell(0, 0), 'a');
+        screen.push_style(inner);
+        screen.put_char(Cell(0, 1), 'b');
+        screen.pop_style();
+        screen.put_char(Cell(0, 2), 'c');
+        screen.pop_style();
+        screen.put_char(Cell(0, 3), 'd');
+        screen.pop_style();
+        screen.put_char(Cell(0, 4), 'e');
+        let style_at = |col| screen.buffer.get(Cell(0, col)).map(|(_, style)| style);
+        assert_eq!(style_at(0), Some(outer));
+        assert_eq!(style_at(1), Some(inner));
+        assert_eq!(style_at(2), Some(outer));
+        assert_eq!(style_at(3), Some(base));
+        assert_eq!(style_at(4), Some(base));
+    }
+
+    #[test]
+    fn width_override() {
+        let style = Style::new(Color::White, Color::Black);
+        let (mut screen, output) = screen_with_output(Size(1, 6));
+        assert!(screen.set_width_override('x', 3).is_err());
+        screen.set_width_override('x', 2).unwrap();
+        screen.flush();
+        output.take();
+        assert_eq!(screen.put_str(Cell(0, 0), "axb", style), 4);
+        assert_eq!(screen.buffer.get(Cell(0, 1)), Some(('x', style)));
+        assert_eq!(screen.buffer.get(Cell(0, 2)), None);
+        assert_eq!(screen.buffer.span(Cell(0, 1)), 2);
+        assert_eq!(screen.buffer.get(Cell(0, 3)), Some(('b', style)));
+        screen.put(Cell(0, 5), 'c', Color::White, Color::Black);
+        screen.flush();
+        // there's no telling where the cursor ends up after an overridden width
+        assert_eq!(
+            output.take(),
+            "\x1B[1;1H\x1B[37m\x1B[40max\x1B[1;4Hb\x1B[1Cc"
+        );
+    }
+
+    #[test]
+    fn put_fold() {
+        let style = Style::new(Color::White, Color::Blue);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(3, 12));
+        let rect = Rect(Cell(1, 2), Size(2, 8));
+        screen.put_fold(1, rect, "fn f", '+', style);
+        assert_eq!(row_text(&screen, 1), "+fn f   ");
+        assert_eq!(screen.buffer.get(Cell(1, 2)), Some(('+', style)));
+        assert_eq!(screen.buffer.get(Cell(1, 9)), Some((' ', style)));
+        assert_eq!(screen.buffer.get(Cell(1, 10)), None);
+        screen.put_fold(2, rect, "fn main() {", '+', style);
+        assert_eq!(row_text(&screen, 2), "+fn mai…");
+        screen.put_fold(0, rect, "fn main", '+', style);
+        assert_eq!(row_text(&screen, 0), "");
+    }
+
+    #[test]
+    fn put_str_aligned() {
+        let style = Style::new(Color::White, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(4, 80));
+        screen.put_str_aligned(0, "12:00", Align::Right, Color::White, Color::Black);
+        assert_eq!(screen.buffer.get(Cell(0, 74)), None);
+        assert_eq!(screen.buffer.get(Cell(0, 75)), Some(('1', style)));
+        assert_eq!(screen.buffer.get(Cell(0, 79)), Some(('0', style)));
+        screen.put_str_aligned(1, "あいう", Align::Center, Color::White, Color::Black);
+        assert_eq!(screen.buffer.get(Cell(1, 37)), Some(('あ', style)));
+        screen.put_str_aligned(2, "title", Align::Left, Color::White, Color::Black);
+        assert_eq!(row_text(&screen, 2), "title");
+        let long = "x".repeat(90);
+        screen.put_str_aligned(3, &long, Align::Right, Color::White, Color::Black);
+        assert_eq!(row_text(&screen, 3), "x".repeat(80));
+    }
+
+    #[test]
+    fn put_link() {
+        let style = Style::new(Color::White, Color::Black);
+        let (mut screen, output) = screen_with_output(Size(2, 10));
+        let url = "https://example.com";
+        screen.put_link(Cell(0, 0), "a b", url, Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[1;1H\x1B[37m\x1B[40m\x1B]8;;https://example.com\x07a b\x1B]8;;\x07"
+        );
+
+        // the same link drawn again is left be, and when it has to be written
+        // again it's written as a link
+        screen.put_link(Cell(0, 0), "a b", url, Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(output.take(), "");
+        screen.invalidate();
+        screen.put_link(Cell(0, 0), "a b", url, Color::White, Color::Black);
+        screen.flush();
+        assert!(output.take().starts_with(
+            "\x1B[1;1H\x1B[37m\x1B[40m\x1B]8;;https://example.com\x07a b\x1B]8;;\x07"
+        ));
+
+        // the same text that isn't a link is written again without the link
+        screen.put_str(Cell(0, 0), "a b", style);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1;1H\x1B[37m\x1B[40ma b");
+    }
+
+    #[test]
+    fn viewport_clipping() {
+        let style = Style::new(Color::White, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(6, 10));
+        let mut viewport = Viewport::new(&mut screen, Cell(2, 3), Size(2, 4));
+        viewport.fill(Rect(Cell(0, 1), Size(5, 5)), '.', style);
+        viewport.put(Cell(0, 0), 'a', style);
+        viewport.put(Cell(2, 0), 'x', style);
+        viewport.put(Cell(0, 4), 'x', style);
+        viewport.put(Cell(100, 100), 'x', style);
+        viewport.put(Cell(0, 3), 'あ', style);
+        assert_eq!(viewport.put_str(Cell(1, 1), "bcdef", style), 3);
+        assert_eq!(row_text(&screen, 1), "");
+        assert_eq!(row_text(&screen, 2), "a...");
+        assert_eq!(row_text(&screen, 3), "bcd");
+        assert_eq!(row_text(&screen, 4), "");
+        assert_eq!(screen.buffer.get(Cell(2, 3)), Some(('a', style)));
+        assert_eq!(screen.buffer.get(Cell(2, 7)), None);
+
+        let mut viewport = Viewport::new(&mut screen, Cell(4, 3), Size(1, 2));
+        assert_eq!(viewport.put_str(Cell(0, 0), "e\u{301}xy", style), 2);
+        assert_eq!(row_text(&screen, 4), "ex");
+        assert_eq!(screen.buffer.tail(Cell(4, 3)), "\u{301}");
+
+        // nothing is put, nor does anything overflow, this far off the screen
+        assert_eq!(screen.put_str(Cell(0, u16::MAX), "ab", style), 0);
+        let mut viewport = Viewport::new(&mut screen, Cell(0, u16::MAX - 1), Size(1, 10));
+        assert_eq!(viewport.put_str(Cell(0, 5), "ab", style), 2);
+        assert_eq!(viewport.put_str(Cell(0, u16::MAX), "ab", style), 0);
+    }
+
+    #[test]
+    fn composite_layers() {
+        let style = Style::new(Color::White, Color::Black);
+        let (mut screen, output) = screen_with_output(Size(2, 5));
+        let mut bottom = Layer::new(Cell(0, 0), Size(2, 5));
+        for cell in CellIterator::new(Rect(Cell(0, 0), Size(2, 5))) {
+            bottom.put(cell, 'a', style);
+        }
+        let mut top = Layer::new(Cell(1, 0), Size(1, 4));
+        top.put(Cell(0, 0), 'b', style);
+        top.put(Cell(0, 2), 'あ', style);
+        let mut compositor = Compositor::new();
+        compositor.push(bottom);
+        let top = compositor.push(top);
+        compositor.flatten(&mut screen);
+        assert_eq!(row_text(&screen, 0), "aaaaa");
+        assert_eq!(row_text(&screen, 1), "baあa");
+        screen.flush();
+        output.take();
+
+        // only the changes are written
+        compositor.flatten(&mut screen);
+        screen.flush();
+        assert_eq!(output.take(), "");
+        compositor
+            .layer_mut(top)
+            .unwrap()
+            .put(Cell(0, 1), 'c', style);
+        compositor.flatten(&mut screen);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[2;2Hc");
+    }
+
+    #[test]
+    fn gutter() {
+        let style = Style::new(Color::BrightBlack, Color::Black);
+        let current_style = Style::new(Color::Yellow, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(4, 10));
+        let rect = Rect(Cell(0, 1), Size(4, 3));
+        let numbers =
+            |screen: &Screen| -> Vec<String> { (0..4).map(|row| row_text(screen, row)).collect() };
+
+        screen.gutter(rect, 97, 98, false, style, current_style);
+        assert_eq!(numbers(&screen), vec![" 98", " 99", "100", "101"]);
+        assert_eq!(screen.buffer.get(Cell(1, 3)), Some(('9', current_style)));
+        assert_eq!(screen.buffer.get(Cell(2, 3)), Some(('0', style)));
+
+        screen.gutter(rect, 0, 2, true, style, current_style);
+        assert_eq!(numbers(&screen), vec!["  2", "  1", "  3", "  1"]);
+        assert_eq!(screen.buffer.get(Cell(2, 3)), Some(('3', current_style)));
+        assert_eq!(screen.buffer.get(Cell(3, 3)), Some(('1', style)));
+
+        screen.gutter(
+            Rect(Cell(0, 1), Size(1, 3)),
+            12344,
+            0,
+            false,
+            style,
+            current_style,
+        );
+        assert_eq!(row_text(&screen, 0), "12…");
+    }
+
+    #[test]
+    fn progress_bar() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(3, 10));
+        screen.progress_bar(
+            Rect(Cell(1, 2), Size(2, 5)),
+            0.5,
+            Color::Green,
+            Color::Black,
+        );
+        for row in 1..3 {
+            let cell = |col| screen.buffer.get(Cell(row, col));
+            assert_eq!(cell(1), None);
+            assert_eq!(cell(2), Some((' ', Style::new(Color::Green, Color::Green))));
+            assert_eq!(cell(3), Some((' ', Style::new(Color::Green, Color::Green))));
+            assert_eq!(cell(4), Some(('▌', Style::new(Color::Green, Color::Black))));
+            assert_eq!(cell(5), Some((' ', Style::new(Color::Black, Color::Black))));
+            assert_eq!(cell(6), Some((' ', Style::new(Color::Black, Color::Black))));
+            assert_eq!(cell(7), None);
+        }
+
+        screen.progress_bar(
+            Rect(Cell(0, 0), Size(1, 10)),
+            1.5,
+            Color::Green,
+            Color::Black,
+        );
+        assert_eq!(
+            screen.buffer.get(Cell(0, 9)),
+            Some((' ', Style::new(Color::Green, Color::Green)))
+        );
+        screen.progress_bar(
+            Rect(Cell(0, 0), Size(1, 10)),
+            0.01,
+            Color::Green,
+            Color::Black,
+        );
+        assert_eq!(
+            screen.buffer.get(Cell(0, 0)),
+            Some(('▏', Style::new(Color::Green, Color::Black)))
+        );
+    }
+
+    #[test]
+    fn geometry_conversions() {
+        assert_eq!(Cell::from((3, 7)), Cell(3, 7));
+        assert_eq!(<(u16, u16)>::from(Cell(3, 7)), (3, 7));
+        assert_eq!(Size::from((24, 80)), Size(24, 80));
+        assert_eq!(<(u16, u16)>::from(Size(24, 80)), (24, 80));
+        assert_eq!(Cell::from(<(u16, u16)>::from(Cell(1, 2))), Cell(1, 2));
+        assert_eq!(Size::from(<(u16, u16)>::from(Size(5, 6))), Size(5, 6));
+        assert_eq!(Size(24, 80).to_cell(), Cell(24, 80));
+        assert_eq!(Cell(24, 80).to_size(), Size(24, 80));
+        assert_eq!(Cell(9, 8).to_size().to_cell(), Cell(9, 8));
+    }
+
+    #[test]
+    fn cell_delta() {
+        let cell = Cell(5, 10);
+        assert_eq!(cell.delta(Cell(7, 13)), (2, 3));
+        assert_eq!(cell.delta(Cell(2, 4)), (-3, -6));
+        assert_eq!(cell.delta(Cell(8, 1)), (3, -9));
+        assert_eq!(cell.delta(cell), (0, 0));
+        assert_eq!(Cell(0, 0).delta(Cell(u16::MAX, 0)), (65535, 0));
+        // where subtracting stops at zero
+        assert_eq!(Cell(2, 4) - cell, Cell(0, 0));
+        assert_eq!(cell.manhattan(Cell(7, 13)), 5);
+        assert_eq!(cell.manhattan(Cell(2, 4)), 9);
+        assert_eq!(Cell(2, 4).manhattan(cell), 9);
+        assert_eq!(cell.manhattan(Cell(8, 1)), 12);
+        assert_eq!(cell.manhattan(cell), 0);
+    }
+
+    #[test]
+    fn clamp_cell() {
+        assert_eq!(Cell(100, 100).clamp_to(Size(24, 80)), Cell(23, 79));
+        assert_eq!(Cell(5, 100).clamp_to(Size(24, 80)), Cell(5, 79));
+        assert_eq!(Cell(100, 6).clamp_to(Size(24, 80)), Cell(23, 6));
+        assert_eq!(Cell(24, 80).clamp_to(Size(24, 80)), Cell(23, 79));
+        assert_eq!(Cell(5, 6).clamp_to(Size(24, 80)), Cell(5, 6));
+        assert_eq!(Cell(23, 79).clamp_to(Size(24, 80)), Cell(23, 79));
+        assert_eq!(Cell(5, 6).clamp_to(Size(0, 80)), Cell(0, 0));
+        assert_eq!(Cell(5, 6).clamp_to(Size(24, 0)), Cell(0, 0));
+        assert_eq!(Cell(100, 6).clamp(Size(24, 80)), Cell(23, 6));
+        assert_eq!(Cell(5, 6).clamp(Size(0, 0)), Cell(0, 0));
+    }
+
+    #[test]
+    fn suspend_and_resume() {
+        let (mut screen, output) = screen_with_output(Size(1, 3));
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.push_keyboard_flags(KeyboardFlags::DISAMBIGUATE_ESCAPE_CODES);
+        screen.set_title("rim");
+        screen.set_cursor_shape(CursorShape::Bar);
+        screen.flush();
+        output.take();
+        screen.set_synchronized_output(true);
+        screen.suspend();
+        let handed_back = "\x1B[<1u\x1B[23;2t\x1B[?1004l\x1B[?25h\x1B[0 q\x1B[?47l\x1B8";
+        assert_eq!(
+            output.take(),
+            format!("\x1B[?2026h{}\x1B[?2026l", handed_back)
+        );
+        // dropped now, there'd be nothing left to pop or restore
+        assert_eq!(
+            screen.terminal.hand_back_sequence(),
+            "\x1B[?1004l\x1B[?25h\x1B[0 q"
+        );
+        screen.resume();
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[?2026h\x1B7\x1B[?47h\x1B[?25l\x1B[?1004h\x1B[>1u\x1B[22;2t\x1B]0;rim\x07\x1B[5 q\
+             \x1B[0m\x1B[2J\x1B[1;1H\x1B[37m\x1B[40mx\x1B[?2026l"
+        );
+        assert_eq!(screen.terminal.hand_back_sequence(), handed_back);
+    }
+
+    #[test]
+    fn suspend_sequence() {
+        let output = Output::default();
+        let mut terminal = Terminal::with_writer(Box::new(output.clone()));
+        terminal.profile = TermProfile {
+            enter_altscreen: "\x1B[?1049h".to_string(),
+            leave_altscreen: "\x1B[?1049l".to_string(),
+            show_cursor: "\x1B[?12l\x1B[?25h".to_string(),
+            ..TermProfile::default()
+        };
+        let mut screen = Screen::with_terminal(terminal);
+        screen.flush();
+        let handed_back = "\x1B[?1004l\x1B[?12l\x1B[?25h\x1B[?1049l";
+        assert_eq!(screen.terminal.hand_back_sequence(), handed_back);
+        output.take();
+        screen.suspend();
+        assert_eq!(output.take(), handed_back);
+        screen.resume();
+        screen.flush();
+        assert_eq!(screen.terminal.hand_back_sequence(), handed_back);
+    }
+
+    #[test]
+    fn focus_reporting() {
+        let output = Output::default();
+        let mut terminal = Terminal::with_writer(Box::new(output.clone()));
+        terminal.enable_focus_reporting();
+        terminal.flush();
+        assert_eq!(output.take(), "\x1B[?1004h");
+        terminal.disable_focus_reporting();
+        terminal.flush();
+        assert_eq!(output.take(), "\x1B[?1004l");
+
+        // the screen has it enabled for as long as it's around
+        let screen = Screen::with_writer(Box::new(output.clone()), Size(1, 1));
+        drop(screen);
+        let written = output.take();
+        let enabled = written.find("\x1B[?1004h").unwrap();
+        assert!(written[enabled..].contains("\x1B[?1004l"));
+    }
+
+    #[test]
+    fn insert_and_delete_lines() {
+        let (mut screen, output) = screen_with_output(Size(4, 3));
+        let style = Style::new(Color::White, Color::Black);
+        for (row, text) in ["a", "bb", "c", "d"].iter().enumerate() {
+            screen.put_str(Cell(row as u16, 0), text, style);
+        }
+        screen.flush();
+        output.take();
+        let rows = |screen: &Screen| (0..4).map(|row| row_text(screen, row)).collect::<Vec<_>>();
+
+        screen.insert_lines_at(1, 2);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[2A\r\x1B[39;49m\x1B[2L");
+        assert_eq!(rows(&screen), vec!["a", "", "", "bb"]);
+        assert_eq!(screen.buffer.get(Cell(3, 1)), Some(('b', style)));
+
+        screen.delete_lines_at(0, 3);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1A\x1B[39;49m\x1B[3M");
+        assert_eq!(rows(&screen), vec!["bb", "", "", ""]);
+
+        // nothing needs drawing again
+        screen.put_str(Cell(0, 0), "bb", style);
+        screen.flush();
+        assert_eq!(output.take(), "");
+    }
+
+    #[test]
+    fn scroll() {
+        let (mut screen, output) = screen_with_output(Size(4, 3));
+        let style = Style::new(Color::White, Color::Black);
+        for (row, text) in ["a", "bb", "c", "d"].iter().enumerate() {
+            screen.put_str(Cell(row as u16, 0), text, style);
+        }
+        screen.flush();
+        output.take();
+        let rows = |screen: &Screen| (0..4).map(|row| row_text(screen, row)).collect::<Vec<_>>();
+
+        // spanning the width of the screen, the terminal scrolls
+        screen.scroll(Rect(Cell(1, 0), Size(2, 3)), 1);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[2A\r\x1B[39;49m\x1B[1M\x1B[1B\x1B[39;49m\x1B[1L"
+        );
+        assert_eq!(rows(&screen), vec!["a", "c", "", "d"]);
+        screen.scroll(Rect(Cell(0, 0), Size(3, 3)), -2);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[1A\x1B[39;49m\x1B[2M\x1B[1A\x1B[39;49m\x1B[2L"
+        );
+        assert_eq!(rows(&screen), vec!["", "", "a", "d"]);
+
+        // otherwise the cells are copied over
+        let (mut screen, output) = screen_with_output(Size(3, 5));
+        for (row, text) in ["abcde", "fあhi", "jklmn"].iter().enumerate() {
+            screen.put_str(Cell(row as u16, 0), text, style);
+        }
+        screen.scroll(Rect(Cell(0, 1), Size(3, 3)), 1);
+        assert_eq!(screen.dump(), "aあhe\nfklmi\nj   n");
+        screen.scroll(Rect(Cell(0, 2), Size(3, 2)), -1);
+        assert_eq!(screen.dump(), "a   e\nfk hi\nj lmn");
+        screen.flush();
+        output.take();
+        // nothing needs drawing again
+        screen.put_str(Cell(1, 0), "fk", style);
+        screen.put_str(Cell(2, 2), "lmn", style);
+        screen.flush();
+        assert_eq!(output.take(), "");
+    }
+
+    #[test]
+    fn insert_and_delete_chars() {
+        let (mut screen, output) = screen_with_output(Size(1, 8));
+        let style = Style::new(Color::White, Color::Black);
+        screen.put_str(Cell(0, 0), "abcdef", style);
+        screen.flush();
+        output.take();
+        let cells = |screen: &Screen| {
+            (0..8)
+                .map(|col| screen.buffer.get(Cell(0, col)).map_or(' ', |(c, _)| c))
+                .collect::<String>()
+        };
+
+        screen.insert_chars_at(Cell(0, 2), 2);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[4D\x1B[39;49m\x1B[2@");
+        assert_eq!(cells(&screen), "ab  cdef");
+        screen.delete_chars_at(Cell(0, 1), 3);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1D\x1B[39;49m\x1B[3P");
+        assert_eq!(cells(&screen), "acdef   ");
+
+        // wide characters aren't left split in two
+        screen.put_str(Cell(0, 0), "aあb", style);
+        screen.flush();
+        output.take();
+        screen.insert_chars_at(Cell(0, 2), 1);
+        assert_eq!(cells(&screen), "a   bf  ");
+        assert!(screen.buffer.is_dirty(Cell(0, 1)) && screen.buffer.is_dirty(Cell(0, 3)));
+    }
+
+    #[test]
+    fn alt_screen() {
+        let (mut screen, output) = screen_with_output(Size(1, 3));
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.enter_alt_screen();
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1;1H\x1B[37m\x1B[40mx");
+        screen.leave_alt_screen();
+        screen.leave_alt_screen();
+        assert_eq!(output.take(), "\x1B[?47l\x1B8");
+        screen.enter_alt_screen();
+        screen.enter_alt_screen();
+        screen.flush();
+        assert_eq!(output.take(), "\x1B7\x1B[?47h\x1B[0m\x1B[2J");
+        assert_eq!(row_text(&screen, 0), "");
+    }
+
+    #[test]
+    fn term_profile() {
+        let output = Output::default();
+        let mut terminal = Terminal::with_writer(Box::new(output.clone()));
+        terminal.profile = TermProfile {
+            enter_altscreen: "\x1B[?1049h".to_string(),
+            leave_altscreen: "\x1B[?1049l".to_string(),
+            clear: "\x1B[H\x1B[2J".to_string(),
+            ..TermProfile::default()
+        };
+        let mut screen = Screen::with_terminal(terminal);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[?1049h\x1B[?25l\x1B[?1004h\x1B[0m\x1B[H\x1B[2J"
+        );
+        screen.leave_alt_screen();
+        assert_eq!(output.take(), "\x1B[?1049l");
+        assert_eq!(without_padding("\x1B[H$<2>\x1B[2J$<50/>"), "\x1B[H\x1B[2J");
+    }
+
+    #[test]
+    fn terminfo_sequences() {
+        let output = Output::default();
+        let mut terminal = Terminal::with_writer(Box::new(output.clone()));
+        // as in xterm's terminfo entry
+        terminal.profile.cursor_address = Some("\x1B[%i%p1%d;%p2%dH".to_string());
+        terminal.profile.set_fg = Some("\x1B[3%p1%dm".to_string());
+        terminal.profile.erase_line = "\x1B[K".to_string();
+        terminal.set_cursor_position(4, 9);
+        terminal.set_cursor_position(5, 9);
+        terminal.set_fg(Color::Red);
+        terminal.set_fg(Color::Rgb(1, 2, 3));
+        terminal.erase_line();
+        terminal.out.flush().unwrap();
+        assert_eq!(
+            output.take(),
+            "\x1B[5;10H\x1B[1B\x1B[31m\x1B[38;2;1;2;3m\x1B[K"
+        );
+        // a terminal addressing the cursor some other way isn't moved relatively
+        terminal.profile.cursor_address = Some("\x1B=%p1%d,%p2%d.".to_string());
+        terminal.set_cursor_position(6, 9);
+        terminal.out.flush().unwrap();
+        assert_eq!(output.take(), "\x1B=6,9.");
+    }
+
+    #[test]
+    fn refresh() {
+        let (mut screen, output) = screen_with_output(Size(1, 3));
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.flush();
+        output.take();
+        screen.refresh();
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B7\x1B[?47h\x1B[?25l\x1B[?1004h\x1B[0m\x1B[2J"
+        );
+        assert_eq!(row_text(&screen, 0), "");
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1;1H\x1B[37m\x1B[40mx");
+    }
+
+    #[test]
+    fn invalidate() {
+        let (mut screen, output) = screen_with_output(Size(2, 3));
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.put(Cell(1, 0), 'y', Color::White, Color::Black);
+        screen.flush();
+        output.take();
+        screen.invalidate();
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.put(Cell(1, 0), 'y', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[1;1H\x1B[37m\x1B[40mx\x1B[39;49m\x1B[0K\x1B[2;1H\x1B[37m\x1B[40my\x1B[39;49m\x1B[0K"
+        );
+
+        screen.invalidate_rect(Rect(Cell(1, 0), Size(5, 1)));
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.put(Cell(1, 0), 'y', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[2;1H\x1B[37m\x1B[40my");
+    }
+
+    #[test]
+    fn sized() {
+        let terminal = Terminal::with_writer(Box::new(io::sink()));
+        let mut screen = Screen::with_terminal(terminal).sized(Some(Size(24, 80)));
+        assert_eq!(screen.size(), Size(24, 80));
+        screen.put(Cell(23, 79), 'x', Color::White, Color::Black);
+        assert_eq!(row_text(&screen, 23), "x");
+        let terminal = Terminal::with_writer(Box::new(io::sink()));
+        assert_eq!(
+            Screen::with_terminal(terminal).sized(None).size(),
+            Size(0, 0)
+        );
+    }
+
+    #[test]
+    fn on_resize() {
+        let sizes = Rc::new(RefCell::new(Vec::new()));
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(2, 3));
+        let seen = sizes.clone();
+        screen.on_resize(move |size| seen.borrow_mut().push(size));
+        assert!(!screen.change_size(Size(2, 3)));
+        assert!(screen.change_size(Size(4, 5)));
+        assert!(!screen.change_size(Size(4, 5)));
+        assert!(screen.change_size(Size(1, 5)));
+        assert_eq!(*sizes.borrow(), vec![Size(4, 5), Size(1, 5)]);
+        assert_eq!(screen.size(), Size(1, 5));
+    }
+
+    #[test]
+    fn resize_keeps_rows() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(2, 80));
+        screen.put(Cell(0, 37), 'a', Color::White, Color::Black);
+        screen.put(Cell(1, 0), 'x', Color::White, Color::Black);
+        screen.resize(Size(3, 80));
+        assert_eq!(row_text(&screen, 0), "a");
+        assert_eq!(row_text(&screen, 1), "x");
+        assert_eq!(row_text(&screen, 2), "");
+        screen.resize(Size(1, 80));
+        assert_eq!(row_text(&screen, 0), "a");
+    }
+
+    #[test]
+    fn resize_keeps_allocation() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(60, 100));
+        let allocation = |screen: &Screen| {
+            let buffer = &screen.buffer;
+            (
+                buffer.cells.chars.as_ptr(),
+                buffer.cells.chars.capacity(),
+                buffer.cells.styles.capacity(),
+                buffer.dirty.capacity(),
+            )
+        };
+        let before = allocation(&screen);
+        for _ in 0..10 {
+            screen.resize(Size(50, 80));
+            screen.resize(Size(40, 120));
+            screen.resize(Size(60, 100));
+        }
+        assert_eq!(allocation(&screen), before);
+    }
+
+    #[test]
+    fn redraw_after_width_change() {
+        let (mut screen, output) = screen_with_output(Size(2, 80));
+        screen.put(Cell(1, 0), 'x', Color::White, Color::Black);
+        screen.flush();
+        output.take();
+        screen.resize(Size(2, 40));
+        assert_eq!(row_text(&screen, 1), "");
+        screen.put(Cell(1, 0), 'x', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[2;1Hx");
+    }
+
+    #[test]
+    fn zero_size() {
+        let style = Style::new(Color::White, Color::Black);
+        for &size in &[Size(0, 0), Size(0, 80), Size(24, 0)] {
+            let (mut screen, output) = screen_with_output(Size(2, 4));
+            screen.put(Cell(1, 3), 'x', Color::White, Color::Black);
+            screen.resize(size);
+            screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+            screen.put_str(Cell(0, 0), "abc", style);
+            screen.fill_rect(
+                Rect(Cell(0, 0), Size(2, 2)),
+                'x',
+                Color::White,
+                Color::Black,
+            );
+            screen.draw_box(Rect(Cell(0, 0), Size(3, 3)), Color::White, Color::Black);
+            screen.status_line(0, "left", "center", "right", style);
+            screen.highlight_row(0, Color::Red);
+            screen.draw_cursor(Cell(0, 0));
+            screen.insert_lines_at(0, 1);
+            screen.delete_chars_at(Cell(0, 0), 1);
+            screen.invalidate();
+            screen.park_cursor();
+            screen.flush();
+            assert_eq!(output.take(), "", "{:?}", size);
+            assert_eq!(
+                screen.dump(),
+                "\n".repeat(size.0.saturating_sub(1) as usize)
+            );
+            // drawing picks up again once there's room for it
+            screen.resize(Size(1, 2));
+            screen.put(Cell(0, 1), 'y', Color::White, Color::Black);
+            assert_eq!(screen.dump(), " y");
+        }
+    }
+
+    #[test]
+    fn size_area() {
+        assert_eq!(Size(3, 4).area(), 12);
+        assert_eq!(Size(0, 100).area(), 0);
+        assert_eq!(Size(1000, 1000).area(), 1_000_000);
+        assert_eq!(Size(u16::MAX, u16::MAX).area(), 65535 * 65535);
+        assert!(Size(0, 100).is_empty());
+        assert!(Size(100, 0).is_empty());
+        assert!(!Size(1, 1).is_empty());
+    }
+
+    #[test]
+    fn const_geometry() {
+        const TITLE_BAR: Rect = Rect::new(Cell::new(0, 0), Size::new(1, 80));
+        const CORNER: Cell = Size::new(1, 80).to_cell();
+        const _: () = assert!(TITLE_BAR.contains(Cell::new(0, 79)));
+        const _: () = assert!(!TITLE_BAR.contains(CORNER));
+        const AREA: usize = TITLE_BAR.1.area();
+        assert_eq!(TITLE_BAR, Rect(Cell(0, 0), Size(1, 80)));
+        assert_eq!(CORNER.to_size(), Size(1, 80));
+        assert_eq!(AREA, 80);
+        assert_eq!(Cell::new(0, 5).within(Size::new(1, 5)), None);
+    }
+
+    #[test]
+    fn rect_rows() {
+        let rows: Vec<Rect> = Rect(Cell(1, 2), Size(3, 5)).rows().collect();
+        assert_eq!(
+            rows,
+            vec![
+                Rect(Cell(1, 2), Size(1, 5)),
+                Rect(Cell(2, 2), Size(1, 5)),
+                Rect(Cell(3, 2), Size(1, 5)),
+            ]
+        );
+        assert_eq!(Rect(Cell(1, 2), Size(0, 5)).rows().count(), 0);
+    }
+
+    #[test]
+    fn vsplit() {
+        let style = Style::new(Color::White, Color::Black);
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(3, 14));
+        let rect = Rect(Cell(0, 2), Size(3, 11));
+        let (left, right) = screen.vsplit(rect, 0.5, '│', style);
+        assert_eq!(left, Rect(Cell(0, 2), Size(3, 5)));
+        assert_eq!(right, Rect(Cell(0, 8), Size(3, 5)));
+        for row in 0..3 {
+            let divider = Cell(row, 7);
+            assert!(!left.contains(divider) && !right.contains(divider));
+            assert_eq!(screen.buffer.get(divider), Some(('│', style)));
+            assert_eq!(screen.buffer.get(Cell(row, 6)), None);
+            assert_eq!(screen.buffer.get(Cell(row, 8)), None);
+        }
+
+        // both sides keep at least one column
+        let (left, right) = screen.vsplit(rect, 0.0, '│', style);
+        assert_eq!(left, Rect(Cell(0, 2), Size(3, 1)));
+        assert_eq!(right, Rect(Cell(0, 4), Size(3, 9)));
+        let (left, right) = screen.vsplit(rect, 1.0, '│', style);
+        assert_eq!(left, Rect(Cell(0, 2), Size(3, 9)));
+        assert_eq!(right, Rect(Cell(0, 12), Size(3, 1)));
+    }
+
+    #[test]
+    fn cell_iterator_len() {
+        let mut cells = CellIterator::new(Rect(Cell(2, 3), Size(3, 4)));
+        for remaining in (0..=12).rev() {
+            assert_eq!(cells.len(), remaining);
+            assert_eq!(cells.clone().count(), remaining);
+            cells.next();
+        }
+        assert_eq!(CellIterator::new(Rect(Cell(1, 1), Size(0, 5))).len(), 0);
+    }
+
+    #[test]
+    fn relative_cursor_movement() {
+        let (mut screen, output) = screen_with_output(Size(10, 80));
+        screen.put(Cell(5, 10), 'a', Color::White, Color::Black);
+        screen.put(Cell(5, 12), 'b', Color::White, Color::Black);
+        screen.put(Cell(5, 13), 'c', Color::White, Color::Black);
+        screen.put(Cell(6, 0), 'd', Color::White, Color::Black);
+        screen.put(Cell(9, 79), 'e', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[6;11H\x1B[37m\x1B[40ma\x1B[1Cbc\x1B[1B\rd\x1B[10;80He"
+        );
+    }
+
+    #[test]
+    fn no_control_sequences_without_tty() {
+        let output = Output::default();
+        let mut terminal = Terminal::with_writer(Box::new(output.clone()));
+        terminal.tty = false;
+        Screen::with_terminal(terminal).flush();
+        assert_eq!(output.take(), "");
+    }
+
+    #[test]
+    fn plain_terminal() {
+        let output = Output::default();
+        let mut screen = Screen::with_terminal(Terminal::plain(Box::new(output.clone())));
+        screen.resize(Size(3, 6));
+        assert!(screen.is_plain());
+        let mut style = Style::new(Color::Red, Color::Blue);
+        style.attrs = Attrs::BOLD;
+        screen.put_str(Cell(0, 0), "ab", style);
+        screen.put_str(Cell(0, 3), "cd", style);
+        screen.put_str(Cell(2, 1), "ef", Style::new(Color::Green, Color::Black));
+        screen.flush();
+        // a plain terminal carries on from wherever a resize leaves it
+        assert_eq!(output.take(), "\r\nab cd\r\n\r\n ef");
+        screen.clear();
+        screen.insert_lines_at(0, 1);
+        screen.put(Cell(0, 0), 'g', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(output.take(), "\r\ng");
+        assert!(!Screen::with_writer(Box::new(io::sink()), Size(1, 1)).is_plain());
+    }
+
+    #[test]
+    fn dumb_terminal() {
+        assert!(dumb_term(None));
+        assert!(dumb_term(Some("")));
+        assert!(dumb_term(Some("dumb")));
+        assert!(!dumb_term(Some("xterm-256color")));
+        let output = Output::default();
+        let mut screen = Screen::with_terminal(Terminal::plain(Box::new(output.clone())));
+        screen.resize(Size(1, 1));
+        output.take();
+        screen.put(Cell(0, 0), 'x', Color::Red, Color::Blue);
+        screen.flush();
+        // the output starts off on a line of its own, but is otherwise bare
+        assert_eq!(output.take(), "\r\nx");
+    }
+
+    #[test]
+    fn park_cursor() {
+        let (mut screen, output) = screen_with_output(Size(24, 80));
+        screen.put(Cell(3, 3), 'x', Color::White, Color::Black);
+        screen.park_cursor();
+        screen.flush();
+        assert!(output.take().ends_with("x\x1B[24;80H"));
+
+        let (mut screen, output) = screen_with_output(Size(0, 0));
+        screen.park_cursor();
+        screen.flush();
+        assert_eq!(output.take(), "");
+    }
+
+    #[test]
+    fn cursor_left_at_position() {
+        let (mut screen, output) = screen_with_output(Size(4, 10));
+        screen.set_cursor_position(Cell(1, 2));
+        screen.put(Cell(0, 0), 'a', Color::White, Color::Black);
+        screen.put(Cell(3, 5), 'b', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[1;1H\x1B[37m\x1B[40ma\x1B[4;6Hb\x1B[2;3H"
+        );
+        // the cursor goes back to where it's left after each frame
+        screen.put(Cell(2, 8), 'c', Color::White, Color::Black);
+        screen.flush();
+        assert!(output.take().ends_with("c\x1B[2;3H"));
+        // and isn't moved when it's already there
+        screen.flush();
+        assert_eq!(output.take(), "");
+        // nor when it's ended up off the screen
+        screen.set_cursor_position(Cell(3, 9));
+        screen.resize(Size(2, 10));
+        screen.put(Cell(0, 1), 'd', Color::White, Color::Black);
+        screen.flush();
+        assert!(output.take().ends_with('d'));
+    }
+
+    #[test]
+    fn cursor_hidden_while_flushing() {
+        let (mut screen, output) = screen_with_output(Size(3, 10));
+        screen.set_cursor_visible(true);
+        screen.flush();
+        output.take();
+        let style = Style::new(Color::White, Color::Black);
+        screen.put_str(Cell(0, 0), "one", style);
+        screen.put_str(Cell(2, 4), "two", style);
+        screen.set_cursor_position(Cell(1, 1));
+        screen.flush();
+        let written = output.take();
+        assert!(written.starts_with("\x1B[?25l\x1B[1;1H"));
+        assert!(written.ends_with("two\x1B[2;2H\x1B[?25h"));
+        assert_eq!(written.matches("\x1B[?25l").count(), 1);
+        assert_eq!(written.matches("\x1B[?25h").count(), 1);
+        // with nothing to write the cursor is left be
+        screen.set_cursor_position(Cell(1, 2));
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1C");
+        // as it is while hidden anyway
+        screen.set_cursor_visible(false);
+        screen.put_str(Cell(0, 0), "three", style);
+        screen.flush();
+        assert!(!output.take().contains("\x1B[?25h"));
+    }
+
+    #[test]
+    fn line_size() {
+        let (mut screen, output) = screen_with_output(Size(3, 10));
+        let style = Style::new(Color::White, Color::Black);
+        screen.put_str(Cell(0, 0), "0123456789", style);
+        screen.flush();
+        output.take();
+        screen.set_line_size(0, LineSize::DoubleWidth);
+        assert_eq!(screen.put_str(Cell(0, 3), "abcdef", style), 2);
+        screen.put(Cell(0, 7), 'x', Color::White, Color::Black);
+        screen.put_str(Cell(1, 3), "abcdef", style);
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1;1H\x1B#6\x1B[3Cab\x1B[2;4Habcdef");
+        assert_eq!(row_text(&screen, 0), "012ab");
+        assert_eq!(row_text(&screen, 1), "abcdef");
+        // the line size moves along with the line
+        screen.insert_lines_at(0, 1);
+        assert_eq!(screen.buffer.line_size(1), LineSize::DoubleWidth);
+        assert_eq!(screen.buffer.line_size(0), LineSize::Normal);
+        screen.set_line_size(1, LineSize::Normal);
+        screen.put_str(Cell(1, 5), "xyz", style);
+        assert_eq!(row_text(&screen, 1), "012abxyz");
+    }
+
+    #[test]
+    fn save_and_restore_cursor() {
+        let (mut screen, output) = screen_with_output(Size(4, 10));
+        screen.set_cursor_position(Cell(1, 2));
+        screen.save_cursor();
+        screen.put(Cell(3, 0), 'x', Color::White, Color::Black);
+        screen.restore_cursor();
+        screen.flush();
+        // having been restored, the cursor is moved absolutely to where it's
+        // left at the end of the flush
+        assert_eq!(
+            output.take(),
+            "\x1B[2;3H\x1B[s\x1B[2B\r\x1B[37m\x1B[40mx\x1B[u\x1B[2;3H"
+        );
+        screen.set_cursor_position(Cell(1, 3));
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1C");
+    }
+
+    #[test]
+    fn last_frame_stats() {
+        let (mut screen, output) = screen_with_output(Size(2, 20));
+        let style = Style::new(Color::White, Color::Black);
+        screen.put_str(Cell(0, 0), "0123456789", style);
+        // unchanged cells aren't written again
+        screen.put_str(Cell(0, 0), "0123", style);
+        screen.flush();
+        let stats = screen.last_frame_stats();
+        assert_eq!(stats.cells_written, 10);
+        assert_eq!(stats.bytes_written, output.take().len());
+        screen.flush();
+        assert_eq!(screen.last_frame_stats().cells_written, 0);
+        assert_eq!(screen.last_frame_stats().bytes_written, 0);
+    }
+
+    #[test]
+    fn stats() {
+        let (mut screen, output) = screen_with_output(Size(2, 20));
+        screen.reset_stats();
+        let style = Style::new(Color::White, Color::Black);
+        screen.put_str(Cell(0, 0), "01234", style);
+        screen.flush();
+        let stats = screen.stats();
+        assert_eq!(stats.cells_written, 5);
+        assert_eq!(stats.flushes, 1);
+        let mut bytes = output.take().len();
+        assert_eq!(stats.bytes_written, bytes);
+        screen.put_str(Cell(1, 0), "567", style);
+        screen.flush();
+        bytes += output.take().len();
+        let stats = screen.stats();
+        assert_eq!(stats.cells_written, 8);
+        assert_eq!(stats.bytes_written, bytes);
+        assert_eq!(stats.flushes, 2);
+        assert_eq!(stats.flush_micros, screen.last_frame_stats().flush_micros);
+        screen.reset_stats();
+        assert_eq!(screen.stats(), RenderStats::default());
+    }
+
+    #[test]
+    fn max_fps() {
+        let (mut screen, output) = screen_with_output(Size(1, 4));
+        screen.set_max_fps(50);
+        // well clear of the flush setting up the screen
+        let start = Instant::now() + Duration::from_secs(1);
+        let after = |millis| start + Duration::from_millis(millis);
+        screen.put(Cell(0, 0), 'a', Color::White, Color::Black);
+        screen.flush_at(start);
+        assert_eq!(output.take(), "\x1B[1;1H\x1B[37m\x1B[40ma");
+        screen.put(Cell(0, 1), 'b', Color::White, Color::Black);
+        screen.flush_at(after(10));
+        assert_eq!(output.take(), "");
+        screen.flush_at(after(20));
+        assert_eq!(output.take(), "b");
+        screen.put(Cell(0, 2), 'c', Color::White, Color::Black);
+        screen.flush_at(after(30));
+        assert_eq!(output.take(), "");
+        screen.force_flush();
+        assert_eq!(output.take(), "c");
+        screen.set_max_fps(0);
+        screen.put(Cell(0, 3), 'd', Color::White, Color::Black);
+        screen.flush_at(after(31));
+        assert_eq!(output.take(), "d");
+    }
+
+    #[test]
+    fn flush_threshold() {
+        let (mut screen, output) = screen_with_output(Size(10, 10));
+        screen.draw_hline(Cell(0, 0), 10, 'x', Color::White, Color::Black);
+        screen.present();
+        assert_eq!(output.take(), "");
+
+        screen.set_flush_threshold(Some(32));
+        for row in 0..10 {
+            screen.draw_hline(Cell(row, 0), 10, 'y', Color::White, Color::Black);
+        }
+        screen.present();
+        assert!(output.take().len() > 100);
+        assert!(screen.terminal.out.buffer.len() <= 32);
+
+        screen.flush();
+        output.take();
+
+        // crossing the threshold doesn't end a synchronized update
+        screen.set_synchronized_output(true);
+        for row in 0..10 {
+            screen.draw_hline(Cell(row, 0), 10, 'z', Color::White, Color::Black);
+        }
+        screen.present();
+        let mut written = output.take();
+        assert!(written.len() > 100);
+        screen.flush();
+        written += &output.take();
+        assert!(written.starts_with("\x1B[?2026h"));
+        assert!(written.ends_with("\x1B[?2026l"));
+        assert_eq!(written.matches("\x1B[?2026h").count(), 1);
+        assert_eq!(written.matches("\x1B[?2026l").count(), 1);
+    }
+
+    #[test]
+    fn keyboard_flags() {
+        let (mut screen, output) = screen_with_output(Size(1, 1));
+        let flags = KeyboardFlags::DISAMBIGUATE_ESCAPE_CODES | KeyboardFlags::REPORT_EVENT_TYPES;
+        screen.push_keyboard_flags(flags);
+        screen.push_keyboard_flags(KeyboardFlags::REPORT_ALL_KEYS_AS_ESCAPE);
+        screen.pop_keyboard_flags();
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[>3u\x1B[>8u\x1B[<u");
+        screen.pop_keyboard_flags();
+        screen.pop_keyboard_flags();
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[<u");
+
+        screen.push_keyboard_flags(flags);
+        screen.push_keyboard_flags(flags);
+        drop(screen);
+        assert!(output
+            .take()
+            .starts_with("\x1B[>3u\x1B[>3u\x1B[<2u\x1B[0m\x1B[2J"));
+    }
+
+    #[test]
+    fn keyboard_flags_reply() {
+        assert_eq!(
+            parse_keyboard_flags(b"\x1B[?5u\x1B[?62;22c"),
+            Some(KeyboardFlags::DISAMBIGUATE_ESCAPE_CODES | KeyboardFlags::REPORT_ALTERNATE_KEYS)
+        );
+        assert_eq!(
+            parse_keyboard_flags(b"\x1B[?0u\x1B[?1;2c"),
+            Some(KeyboardFlags::empty())
+        );
+        assert_eq!(parse_keyboard_flags(b"\x1B[?62;22c"), None);
+        assert_eq!(parse_keyboard_flags(b""), None);
+    }
+
+    #[test]
+    fn synchronized_output() {
+        let (mut screen, output) = screen_with_output(Size(1, 3));
+        screen.set_synchronized_output(true);
+        screen.put(Cell(0, 0), 'x', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[?2026h\x1B[1;1H\x1B[37m\x1B[40mx\x1B[?2026l"
+        );
+        screen.flush();
+        assert_eq!(output.take(), "");
+        screen.set_synchronized_output(false);
+        screen.put(Cell(0, 1), 'y', Color::White, Color::Black);
+        screen.flush();
+        assert_eq!(output.take(), "y");
+    }
+
+    #[test]
+    fn synchronized_output_reply() {
+        assert!(parse_synchronized_output(b"\x1B[?2026;2$y\x1B[?62;22c"));
+        assert!(parse_synchronized_output(b"\x1B[?2026;1$y\x1B[?62;22c"));
+        assert!(!parse_synchronized_output(b"\x1B[?2026;0$y\x1B[?62;22c"));
+        assert!(!parse_synchronized_output(b"\x1B[?2026;4$y\x1B[?62;22c"));
+        assert!(!parse_synchronized_output(b"\x1B[?62;22c"));
+    }
+
+    #[test]
+    fn set_title() {
+        let (mut screen, output) = screen_with_output(Size(1, 1));
+        screen.set_title("evil\x07\x1B]0;pwned\u{9c}.txt");
+        screen.set_title("rim");
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B[22;2t\x1B]0;evil]0;pwned.txt\x07\x1B]0;rim\x07"
+        );
+        drop(screen);
+        assert!(output.take().contains("\x1B[23;2t"));
+    }
+
+    #[test]
+    fn copy_to_clipboard() {
+        let (mut screen, output) = screen_with_output(Size(1, 1));
+        assert!(screen.copy_to_clipboard("rim"));
+        assert!(screen.copy_to_clipboard("hello, world"));
+        assert!(screen.copy_to_clipboard("å"));
+        screen.flush();
+        assert_eq!(
+            output.take(),
+            "\x1B]52;c;cmlt\x07\x1B]52;c;aGVsbG8sIHdvcmxk\x07\x1B]52;c;w6U=\x07"
+        );
+
+        let text = "ä".repeat(MAX_CLIPBOARD_PAYLOAD);
+        assert!(!screen.copy_to_clipboard(&text));
+        screen.flush();
+        assert!(output.take().len() <= MAX_CLIPBOARD_PAYLOAD + 8);
+    }
+
+    #[test]
+    fn blend_colors() {
+        let (red, blue) = (Color::Rgb(200, 0, 10), Color::Rgb(0, 100, 255));
+        assert_eq!(red.blend(blue, 0.0), red);
+        assert_eq!(red.blend(blue, 1.0), blue);
+        assert_eq!(red.blend(blue, 0.5), Color::Rgb(100, 50, 133));
+        assert_eq!(red.blend(blue, 2.0), blue);
+        assert_eq!(
+            Color::BrightWhite.blend(Color::Indexed(16), 0.5),
+            Color::Rgb(128, 128, 128)
+        );
+        assert_eq!(Color::Indexed(196).dim(0.0), Color::Rgb(255, 0, 0));
+        assert_eq!(Color::Indexed(244).dim(0.5), Color::Rgb(64, 64, 64));
+        assert_eq!(Color::White.dim(1.0), Color::Rgb(0, 0, 0));
+    }
+
+    #[test]
+    fn nearest_named_colors() {
+        assert_eq!(Color::Rgb(250, 10, 10).to_nearest_named(), Color::BrightRed);
+        assert_eq!(Color::Rgb(180, 20, 30).to_nearest_named(), Color::Red);
+        assert_eq!(Color::Rgb(30, 30, 30).to_nearest_named(), Color::Black);
+        assert_eq!(
+            Color::Rgb(90, 90, 250).to_nearest_named(),
+            Color::BrightBlue
+        );
+        assert_eq!(Color::Rgb(0, 0, 200).to_nearest_named(), Color::Blue);
+        assert_eq!(Color::Indexed(231).to_nearest_named(), Color::BrightWhite);
+        assert_eq!(Color::Indexed(3).to_nearest_named(), Color::Yellow);
+        assert_eq!(Color::Cyan.to_nearest_named(), Color::Cyan);
+    }
+
+    #[test]
+    fn parse_colors() {
+        assert_eq!("bright_red".parse(), Ok(Color::BrightRed));
+        assert_eq!("black".parse(), Ok(Color::Black));
+        assert_eq!("#1e1E2f".parse(), Ok(Color::Rgb(0x1e, 0x1e, 0x2f)));
+        assert_eq!("0".parse(), Ok(Color::Indexed(0)));
+        assert_eq!("255".parse(), Ok(Color::Indexed(255)));
+        assert!("256".parse::<Color>().is_err());
+        assert!("#1e1e1".parse::<Color>().is_err());
+        assert!("#+1e1e1".parse::<Color>().is_err());
+        assert!("BrightRed".parse::<Color>().is_err());
+        assert!("".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn display_colors() {
+        for &color in &[
+            Color::BrightMagenta,
+            Color::Rgb(1, 128, 255),
+            Color::Indexed(42),
+        ] {
+            assert_eq!(color.to_string().parse(), Ok(color));
+        }
+        assert_eq!(Color::BrightMagenta.to_string(), "bright_magenta");
+        assert_eq!(Color::Rgb(1, 128, 255).to_string(), "#0180ff");
+        assert_eq!(Color::Indexed(42).to_string(), "42");
+    }
+
+    #[test]
+    fn extended_colors_sent() {
+        let (mut screen, output) = screen_with_output(Size(1, 2));
+        screen.put(Cell(0, 0), 'a', Color::Indexed(208), Color::Rgb(0, 0, 64));
+        screen.flush();
+        assert_eq!(output.take(), "\x1B[1;1H\x1B[38;5;208m\x1B[48;2;0;0;64ma");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_style() {
+        let theme = Theme::light();
+        let style = Style {
+            fg: theme.color(Role::Foreground),
+            bg: Color::Rgb(0x1e, 0x1e, 0x1e),
+            attrs: Attrs::BOLD | Attrs::UNDERLINE,
+        };
+        let json = serde_json::to_string(&style).unwrap();
+        assert_eq!(
+            json,
+            r##"{"fg":"black","bg":"#1e1e1e","attrs":["bold","underline"]}"##
+        );
+        assert_eq!(serde_json::from_str::<Style>(&json).unwrap(), style);
+
+        let style: Style = serde_json::from_str(r#"{"fg":"208","bg":"blue"}"#).unwrap();
+        assert_eq!(style.fg, Color::Indexed(208));
+        assert_eq!(style.attrs, Attrs::empty());
+        assert!(serde_json::from_str::<Style>(r#"{"fg":"mauve","bg":"blue"}"#).is_err());
+    }
+
+    #[test]
+    fn switch_theme() {
+        let mut screen = Screen::with_writer(Box::new(io::sink()), Size(1, 1));
+        assert_eq!(screen.color(Role::Foreground), Color::White);
+        assert_eq!(screen.color(Role::Background), Color::Black);
+        screen.set_theme(Some(Theme::light()));
+        assert_eq!(screen.color(Role::Foreground), Color::Black);
+        assert_eq!(screen.color(Role::Background), Color::BrightWhite);
+        screen.set_theme(None);
+        assert_eq!(screen.color(Role::Foreground), Color::White);
+    }
+}
@@ -8,8 +8,6 @@
 
 use std::cmp;
 
-use unicode_width::UnicodeWidthChar as CharWidth;
-
 use crate::buffer::Buffer;
 use crate::caret;
 use crate::caret::Caret;
@@ -55,6 +53,24 @@ impl View {
         screen::Cell(caret_row, caret_column as u16)
     }
 
+    // the buffer line and column shown at |cell| when drawn at |position|, if
+    // any, with every cell of a wide character mapping to that character
+    #[allow(dead_code)] // there's no mouse support yet
+    pub fn screen_to_buffer(
+        &self,
+        position: screen::Cell,
+        cell: screen::Cell,
+        buffer: &Buffer,
+    ) -> Option<(usize, usize)> {
+        if !screen::Rect(position, self.size).contains(cell) {
+            return None;
+        }
+        let screen::Cell(row, col) = cell - position;
+        let line = self.scroll_line + row as usize;
+        let screen_column = self.scroll_column + col as usize;
+        caret::screen_to_buffer_column(line, screen_column, buffer).map(|column| (line, column))
+    }
+
     pub fn set_scroll(&mut self, line: usize, column: usize) {
         self.scroll_line = line;
         self.scroll_column = column;
@@ -79,7 +95,7 @@ impl View {
         let end = start
             + buffer
                 .get_char_by_line_column(line, column)
-                .and_then(CharWidth::width)
+                .and_then(screen::display_width)
                 .unwrap_or(1)
             - 1;
         self.scroll_column = if start < self.scroll_column {
@@ -122,12 +138,12 @@ impl View {
 
         // helper to put a character on the screen
         let put = |character, cell: screen::Cell, screen: &mut Screen| {
-            use screen::Color::*;
+            use screen::Role::*;
             let highlight = caret_cell.map(|c| c != cell).unwrap_or(false);
             let (fg, bg) = if highlight {
-                (Black, White)
+                (screen.color(Background), screen.color(Foreground))
             } else {
-                (White, Black)
+                (screen.color(Foreground), screen.color(Background))
             };
             screen.put(cell, character, fg, bg);
         };
@@ -147,7 +163,7 @@ impl View {
                 if col >= cols as isize || character == '\n' {
                     break;
                 }
-                let char_width = CharWidth::width(character).unwrap_or(0) as isize;
+                let char_width = screen::display_width(character).unwrap_or(0) as isize;
                 let end_col = col + char_width;
                 if (col < 0 && end_col >= 0) || end_col > cols as isize {
                     // blank out partially visible characters
@@ -228,6 +244,29 @@ mod test {
         assert_eq!(view.caret_position(caret, &buffer), screen::Cell(1, 0));
     }
 
+    #[test]
+    fn screen_to_buffer() {
+        let buffer = Buffer::open(&Path::new("tests/view/caret_position.txt")).unwrap();
+        let mut view = View::new();
+        view.set_size(screen::Size(2, 8));
+        let position = screen::Cell(3, 10);
+        let to_buffer = |view: &View, row, col| {
+            view.screen_to_buffer(position, screen::Cell(row, col), &buffer)
+        };
+        assert_eq!(to_buffer(&view, 3, 10), Some((0, 0)));
+        assert_eq!(to_buffer(&view, 4, 12), Some((1, 1)));
+        assert_eq!(to_buffer(&view, 4, 13), Some((1, 1)));
+        view.set_scroll(1, 1);
+        assert_eq!(to_buffer(&view, 3, 10), Some((1, 0)));
+        assert_eq!(to_buffer(&view, 3, 11), Some((1, 1)));
+        assert_eq!(to_buffer(&view, 4, 12), Some((2, 3)));
+        assert_eq!(to_buffer(&view, 4, 17), Some((2, 8)));
+        assert_eq!(to_buffer(&view, 2, 10), None);
+        assert_eq!(to_buffer(&view, 5, 10), None);
+        assert_eq!(to_buffer(&view, 3, 9), None);
+        assert_eq!(to_buffer(&view, 3, 18), None);
+    }
+
     #[test]
     fn line_clamped_to_view() {
         let mut view = View::new();